@@ -0,0 +1,72 @@
+#include "types.wgsl"
+
+@group(0) @binding(0) var<uniform> ctx: Uniform;
+@group(0) @binding(1) var<uniform> denoise: Denoise;
+@group(0) @binding(2) var<storage, read_write> accumulation: array<Pixel>;
+@group(0) @binding(3) var<storage, read_write> aov: array<Aov>;
+@group(0) @binding(4) var<storage, read_write> denoise_a: array<vec3f>;
+@group(0) @binding(5) var<storage, read_write> denoise_b: array<vec3f>;
+
+@compute
+@workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if global_id.x >= ctx.window.x || global_id.y >= ctx.window.y { return; }
+
+    let pixel = vec2i(global_id.xy);
+    let pixel_idx = global_id.y * ctx.window.x + global_id.x;
+    let stride = 1i << denoise.iteration;
+
+    let color = read_color(pixel_idx);
+    let normal = aov[pixel_idx].normal;
+    let depth = aov[pixel_idx].depth;
+
+    // Each iteration halves the color tolerance so the wider kernels only smooth what is left over
+    let color_phi = denoise.strength * denoise.strength / f32(1u << denoise.iteration);
+
+    var sum = vec3(0.0);
+    var total_weight = 0.0;
+
+    for (var y = -2; y <= 2; y++) {
+        for (var x = -2; x <= 2; x++) {
+            let sample = pixel + vec2i(x, y) * stride;
+            if any(sample < vec2i(0)) || any(sample >= vec2i(ctx.window)) { continue; }
+
+            let sample_idx = u32(sample.y) * ctx.window.x + u32(sample.x);
+            let sample_color = read_color(sample_idx);
+            let sample_aov = aov[sample_idx];
+
+            let color_diff = color - sample_color;
+            let color_weight = exp(-dot(color_diff, color_diff) / max(color_phi, 1e-6));
+            let normal_weight = pow(max(dot(normal, sample_aov.normal), 0.0), 64.0);
+            let depth_weight = exp(-abs(depth - sample_aov.depth) / (0.1 * f32(stride) + 1e-6));
+
+            // Background pixels have no normal, so only let them blend with each other
+            var geometry_weight = normal_weight * depth_weight;
+            if depth == 0.0 && sample_aov.depth == 0.0 { geometry_weight = 1.0; }
+
+            let weight = kernel(x) * kernel(y) * color_weight * geometry_weight;
+            sum += sample_color * weight;
+            total_weight += weight;
+        }
+    }
+
+    let out = sum / max(total_weight, 1e-6);
+    if denoise.iteration % 2 == 0 { denoise_a[pixel_idx] = out; }
+    else { denoise_b[pixel_idx] = out; }
+}
+
+// Pass 0 filters the accumulation buffer, after that the two denoise buffers are ping-ponged
+fn read_color(idx: u32) -> vec3f {
+    if denoise.iteration == 0 { return accumulation[idx].color; }
+    else if denoise.iteration % 2 == 1 { return denoise_a[idx]; }
+    return denoise_b[idx];
+}
+
+// B3 spline, as used in "Edge-Avoiding À-Trous Wavelet Transform for fast Global Illumination Filtering"
+fn kernel(offset: i32) -> f32 {
+    switch abs(offset) {
+        case 0: { return 3.0 / 8.0; }
+        case 1: { return 1.0 / 4.0; }
+        default: { return 1.0 / 16.0; }
+    }
+}
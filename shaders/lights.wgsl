@@ -0,0 +1,153 @@
+// Kinds match `LightKind` + 1, zero is used to pad an empty light list
+const LIGHT_POINT: u32 = 1;
+const LIGHT_SPOT: u32 = 2;
+const LIGHT_DIRECTIONAL: u32 = 3;
+const LIGHT_RECTANGLE: u32 = 4;
+const LIGHT_DISK: u32 = 5;
+
+// Picks a single random light, with the radiance scaled by the inverse of
+// the probability of picking it. With the physical sky enabled the sun is
+// picked like any other light.
+fn pick_light(position: vec3f) -> LightSample {
+    let count = arrayLength(&lights) + u32((ctx.flags & 16) != 0);
+    let index = min(u32(rand() * f32(count)), count - 1);
+
+    var sample: LightSample;
+    if index == arrayLength(&lights) { sample = sample_sun(); }
+    else { sample = sample_light(lights[index], position); }
+
+    sample.radiance *= f32(count);
+    return sample;
+}
+
+// Estimates the light arriving at a lambertian surface, including the
+// cosine term and BRDF normalization.
+fn direct_light(position: vec3f, normal: vec3f) -> vec3f {
+    return surface_light(position, normal, pick_light(position));
+}
+
+// Like `direct_light` but only for the sun. Used without next event
+// estimation, as paths would almost never hit its disk on their own.
+fn direct_sun(position: vec3f, normal: vec3f) -> vec3f {
+    if (ctx.flags & 16) == 0 { return vec3(0.0); }
+    return surface_light(position, normal, sample_sun());
+}
+
+fn surface_light(position: vec3f, normal: vec3f, sample: LightSample) -> vec3f {
+    let cos_theta = dot(normal, sample.direction);
+    if cos_theta <= 0.0 || all(sample.radiance == vec3(0.0)) { return vec3(0.0); }
+
+    let visibility = transmittance(Ray(position + normal * 0.0001, sample.direction), sample.distance);
+    return sample.radiance * visibility * cos_theta / PI;
+}
+
+// Estimates the light scattered along `dir` at a point inside a medium.
+fn scattered_light(position: vec3f, dir: vec3f, anisotropy: f32) -> vec3f {
+    return medium_light(position, dir, anisotropy, pick_light(position));
+}
+
+fn scattered_sun(position: vec3f, dir: vec3f, anisotropy: f32) -> vec3f {
+    if (ctx.flags & 16) == 0 { return vec3(0.0); }
+    return medium_light(position, dir, anisotropy, sample_sun());
+}
+
+fn medium_light(position: vec3f, dir: vec3f, anisotropy: f32, sample: LightSample) -> vec3f {
+    if all(sample.radiance == vec3(0.0)) { return vec3(0.0); }
+
+    let phase = henyey_greenstein(dot(dir, sample.direction), anisotropy);
+    return sample.radiance * phase * transmittance(Ray(position, sample.direction), sample.distance);
+}
+
+fn sample_light(light: Light, position: vec3f) -> LightSample {
+    switch light.kind {
+        case LIGHT_POINT, LIGHT_SPOT: {
+            let point = light.position + rand_unit_vector() * light.radius;
+            let offset = point - position;
+            let distance = length(offset);
+            let direction = offset / distance;
+
+            var radiance = light.emission / (distance * distance);
+            if light.kind == LIGHT_SPOT {
+                // Equal angles give a hard edge, smoothstep is undefined there
+                let cos_inner = max(light.cos_inner, light.cos_outer + 1e-4);
+                radiance *= smoothstep(light.cos_outer, cos_inner, dot(-direction, light.direction));
+            }
+
+            return LightSample(direction, distance, radiance);
+        }
+        case LIGHT_DIRECTIONAL: {
+            let direction = sample_cone(-light.direction, light.cos_outer);
+            return LightSample(direction, 3.40282347e+38f, light.emission);
+        }
+        case LIGHT_RECTANGLE, LIGHT_DISK: {
+            var local = vec2(0.0);
+            var area = 0.0;
+            if light.kind == LIGHT_RECTANGLE {
+                local = (vec2(rand(), rand()) - 0.5) * light.size;
+                area = light.size.x * light.size.y;
+            } else {
+                let r = light.radius * sqrt(rand());
+                let theta = 2.0 * PI * rand();
+                local = vec2(cos(theta), sin(theta)) * r;
+                area = PI * light.radius * light.radius;
+            }
+
+            let point = light.position + tangent_space(light.direction, vec3(local, 0.0));
+            let offset = point - position;
+            let distance = length(offset);
+            let direction = offset / distance;
+
+            // Convert from the area measure to solid angle, lights only emit from their front side
+            let cos_light = dot(-direction, light.direction);
+            if cos_light <= 0.0 { return LightSample(direction, distance, vec3(0.0)); }
+            let radiance = light.emission * cos_light * area / (distance * distance);
+
+            return LightSample(direction, distance, radiance);
+        }
+        default: {
+            return LightSample(vec3(0.0, 1.0, 0.0), 0.0, vec3(0.0));
+        }
+    }
+}
+
+// Uniformly samples a direction within the cone around `axis`, used for
+// lights that cover a small disk in the sky
+fn sample_cone(axis: vec3f, cos_max: f32) -> vec3f {
+    let cos_theta = mix(cos_max, 1.0, rand());
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let phi = 2.0 * PI * rand();
+    let local = vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+    return tangent_space(axis, local);
+}
+
+// Fraction of light that makes it along the ray. Volumes don't block the
+// ray, but attenuate it by the distance travelled inside of them.
+fn transmittance(ray: Ray, distance: f32) -> f32 {
+    // Report every hit as a candidate. Back faces are never culled here, the
+    // exits are needed to know how far the ray went inside each volume.
+    let ray_desc = RayDesc(0x2, 0xFF, 0.001, distance - 0.001, ray.pos, ray.dir);
+
+    var rq: ray_query;
+    rayQueryInitialize(&rq, acceleration, ray_desc);
+
+    // For closed meshes the length inside is the sum of the exit distances
+    // minus the sum of the entry distances, in whatever order they come in.
+    // An exit without an entry means the ray started inside, which the sum
+    // already handles as an entry at zero. Entries without an exit mean the
+    // light is inside, so those volumes extend to the end of the ray.
+    var optical_depth = fog_optical_depth(ray, distance);
+    var open_density = 0.0;
+    while rayQueryProceed(&rq) {
+        let candidate = rayQueryGetCandidateIntersection(&rq);
+        let material = materials[models[candidate.geometry_index].material];
+        if material.tag != 2 { return 0.0; }
+
+        let density = volume_material(material).density;
+        let sign = select(1.0, -1.0, candidate.front_face);
+        optical_depth += sign * candidate.t * density;
+        open_density -= sign * density;
+    }
+
+    if open_density > 1e-6 { optical_depth += open_density * distance; }
+    return exp(-max(optical_depth, 0.0));
+}
@@ -1,5 +1,14 @@
+#include "types.wgsl"
+#include "random.wgsl"
+#include "misc.wgsl"
+#include "ray.wgsl"
+#include "lights.wgsl"
+#include "sky.wgsl"
+#include "volume.wgsl"
+#include "subsurface.wgsl"
+
 @group(0) @binding(0) var<uniform> ctx: Uniform;
-@group(0) @binding(1) var<storage, read_write> accumulation: array<vec3f>;
+@group(0) @binding(1) var<storage, read_write> accumulation: array<Pixel>;
 @group(0) @binding(2) var<storage, read> models: array<Model>;
 @group(0) @binding(3) var acceleration: acceleration_structure;
 
@@ -9,71 +18,224 @@
 @group(0) @binding(6) var texture_sampler: sampler;
 @group(0) @binding(7) var textures: binding_array<texture_2d<f32>>;
 
+@group(0) @binding(8) var<storage, read_write> aov: array<Aov>;
+@group(0) @binding(9) var<storage, read_write> tiles: array<vec2u>;
+@group(0) @binding(10) var<storage, read> lights: array<Light>;
+@group(0) @binding(11) var<storage, read> materials: array<Material>;
+
 const PI: f32 = 3.141592653589793;
 
+// Left empty without the AOVS feature, so nothing reads stale values
+var<private> first_hit: Aov;
+var<workgroup> tile_error: atomic<u32>;
+
 @compute
 @workgroup_size(8, 8, 1)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    if global_id.x >= ctx.window.x || global_id.y >= ctx.window.y { return; }
+    let pixel_pos = tiles[global_id.z] + global_id.xy;
+    let in_bounds = pixel_pos.x < ctx.window.x && pixel_pos.y < ctx.window.y;
+    let pixel_idx = pixel_pos.y * ctx.window.x + pixel_pos.x;
+
+    var pixel = Pixel(vec3(0.0), 0.0, 0);
+    if in_bounds && ctx.accumulation_frame > 0 { pixel = accumulation[pixel_idx]; }
+
+    // Find the noisiest pixel in this workgroup's tile. Errors are all positive,
+    // so their bit patterns sort the same way as the floats themselves.
+    let error = pixel_error(pixel);
+    if in_bounds { atomicMax(&tile_error, bitcast<u32>(error)); }
+    workgroupBarrier();
+    let tile = bitcast<f32>(atomicLoad(&tile_error));
 
-    let pixel_idx = global_id.y * ctx.window.x + global_id.x;
-    let uv = vec2f(global_id.xy) / vec2f(ctx.window);
+    if !in_bounds { return; }
+
+    var samples = ctx.samples;
+    if (ctx.flags & 2) != 0 && pixel.samples >= ctx.adaptive_min_samples {
+        if error <= ctx.adaptive_threshold { return; }
+        let scale = clamp(tile / ctx.adaptive_threshold, 1.0 / f32(ctx.samples), 4.0);
+        samples = max(u32(f32(ctx.samples) * scale), 1u);
+    }
+
+    let uv = vec2f(pixel_pos) / vec2f(ctx.window);
     let pos = vec2f(uv.x, 1.0 - uv.y) - 0.5;
 
     seed = (pixel_idx * 2479898233) ^ (ctx.frame * 98379842);
 
     var color = vec3(0.0);
-    for (var i = 0u; i < ctx.samples; i++) { color += sample(pos); }
-    color /= f32(ctx.samples);
+    var moment = 0.0;
+    for (var i = 0u; i < samples; i++) {
+        start_sample(pixel_idx, pixel.samples + i);
+        let radiance = sample(pos);
+        let lum = luminance(radiance);
+        color += radiance;
+        moment += lum * lum;
+    }
 
-    let mapped = tone_map(color * ctx.exposure);
+    let total = pixel.samples + samples;
+    let weight = f32(samples) / f32(total);
+    accumulation[pixel_idx] = Pixel(
+        mix(pixel.color, color / f32(samples), weight),
+        mix(pixel.moment, moment / f32(samples), weight),
+        total
+    );
+    aov[pixel_idx] = first_hit;
+}
+
+// Relative standard error of the pixel's mean luminance
+fn pixel_error(pixel: Pixel) -> f32 {
+    if pixel.samples < 2 { return 3.40282347e+38f; }
 
-    let out = mix(accumulation[pixel_idx], mapped, 1.0 / f32(ctx.accumulation_frame + 1));
-    accumulation[pixel_idx] = out;
+    let mean = luminance(pixel.color);
+    let variance = max(pixel.moment - mean * mean, 0.0) / f32(pixel.samples);
+    return sqrt(variance) / max(mean, 1e-4);
 }
 
 fn sample(pos: vec2f) -> vec3f {
     let offset = (vec2(rand(), rand()) * 2.0 - 1.0) / vec2f(ctx.window);
     let dir = ray_direction(pos + offset);
-    var ray = Ray(ctx.camera.pos, dir);
+    var ray = Ray(ctx.camera.position, dir);
 
     var light = vec3(0.0);
     var color = vec3(1.0);
+    // If the sun was already sampled directly at the last hit, which happens
+    // with or without next event estimation
+    var sampled_sun = false;
+
+    // The medium the ray is travelling through, changed when passing into a volume
+    var medium = ctx.fog.medium;
+    var medium_height = ctx.fog.height;
+    var inside_volume = false;
 
     for (var bounce = 0u; bounce <= ctx.max_bounces; bounce++) {
-        let trace = trace_ray(ray);
+        var trace: Intersection;
+        if inside_volume { trace = trace_ray_inside(ray); }
+        else { trace = trace_ray(ray); }
 
-        if !trace.hit {
-            light += background_color(ray.dir) * color * ctx.enviroment;
-            // light += vec3(0.3) * color * ctx.enviroment;
-            break;
+#ifdef AOVS
+        if bounce == 0 {
+            first_hit = Aov(vec3(0.0), 0.0, 0);
+            if trace.hit { first_hit = Aov(normalize(trace.normal), distance(ray.pos, trace.position), trace.model + 1); }
         }
+#endif
+
+        // Free-flight sampling, the ray may scatter in the medium before reaching the surface
+        let scatter_distance = free_flight(ray, trace.distance, medium, medium_height);
 
-        // 0 => Metal; 1 => Dielectric
-        if trace.material.tag == 0 {
-            let material = trace.material.metal;
+        // 0 => Metal; 1 => Dielectric; 2 => Volume; 3 => Subsurface
+        if scatter_distance < trace.distance {
+            let position = ray.pos + ray.dir * scatter_distance;
+            color *= medium.albedo;
+#ifdef NEXT_EVENT_ESTIMATION
+            let direct = scattered_light(position, ray.dir, medium.anisotropy);
+#else
+            let direct = scattered_sun(position, ray.dir, medium.anisotropy);
+#endif
+            light += clamp_contribution(direct * color, bounce);
+            sampled_sun = true;
+
+            ray = Ray(position, sample_henyey_greenstein(ray.dir, medium.anisotropy));
+        } else if !trace.hit {
+            var background = background_color(ray.dir) * ctx.environment;
+            if !sampled_sun { background += sun_disk(ray.dir); }
+            light += clamp_contribution(background * color, bounce);
+            // light += vec3(0.3) * color * ctx.environment;
+            break;
+        } else if trace.material.tag == 0 {
+            let material = metal_material(trace.material);
 
             let emitted = material.emission_color * material.emission_strength;
             let scatter = get_scattered_direction_metal(ray, trace, material);
-            light += emitted * color;
+            light += clamp_contribution(emitted * color, bounce);
+            if scatter.diffuse {
+#ifdef NEXT_EVENT_ESTIMATION
+                let direct = direct_light(trace.position, normalize(scatter.normal));
+#else
+                let direct = direct_sun(trace.position, normalize(scatter.normal));
+#endif
+                light += clamp_contribution(direct * scatter.color * color, bounce);
+            }
+            sampled_sun = scatter.diffuse;
             color *= scatter.color;
 
             ray = Ray(trace.position + trace.normal * 0.0001, scatter.direction);
         } else if trace.material.tag == 1 {
-            let material = trace.material.dielectric;
-            let next_dir = get_scattered_direction_dielectric(ray, trace, material);
+            let material = dielectric_material(trace.material);
+            let scatter = get_scattered_direction_dielectric(ray, trace, material);
+            color *= scatter.color;
+            sampled_sun = false;
+
+            // Offset to whichever side the ray continues on
+            let offset_dir = trace.normal * sign(dot(scatter.direction, trace.normal));
+            ray = Ray(trace.position + offset_dir * 0.0001, scatter.direction);
+        } else if trace.material.tag == 2 {
+            // Volume boundaries don't scatter, the ray just changes medium
+            inside_volume = trace.front_face;
+            if trace.front_face {
+                medium = volume_material(trace.material);
+                medium_height = 3.40282347e+38f;
+            } else {
+                medium = ctx.fog.medium;
+                medium_height = ctx.fog.height;
+            }
 
-            let offset_dir = trace.normal - 2.0 * trace.normal * f32(trace.front_face);
-            ray = Ray(trace.position + offset_dir * 0.0001, next_dir);
+            ray = Ray(trace.position + ray.dir * 0.0001, ray.dir);
+        } else if trace.material.tag == 3 {
+            let material = subsurface_material(trace.material);
+            let normal = faceForward(trace.normal, trace.normal, ray.dir);
+            let cos_theta = min(dot(-ray.dir, normal), 1.0);
+
+            if rand() < schlick_approximation(cos_theta, material.refractive_index) {
+                sampled_sun = false;
+                ray = Ray(trace.position + normal * 0.0001, reflect(ray.dir, normal));
+            } else {
+                let walk = subsurface_walk(trace.position, normal, material);
+                if !walk.exited { break; }
+
+                // Light leaves the surface diffusely where the walk exits
+                color *= walk.throughput;
+#ifdef NEXT_EVENT_ESTIMATION
+                let direct = direct_light(walk.position, walk.normal);
+#else
+                let direct = direct_sun(walk.position, walk.normal);
+#endif
+                light += clamp_contribution(direct * color, bounce);
+                sampled_sun = true;
+
+                let direction = rand_cosine_hemisphere_vector(walk.normal);
+                ray = Ray(walk.position + walk.normal * 0.0001, direction);
+            }
+        }
+
+        // Russian roulette, randomly end paths that can't contribute much
+        // more light and boost the survivors to keep the estimate unbiased.
+        if (ctx.flags & 8) != 0 && bounce >= ctx.roulette_depth {
+            let survival = min(max(color.x, max(color.y, color.z)), 0.95);
+            if rand() >= survival { break; }
+            color /= survival;
         }
     }
 
     return light;
 }
 
-// Docs for rayQuery functions: https://github.com/gfx-rs/wgpu/blob/trunk/etc/specs/ray_tracing.md
+// Limits the brightness of indirect light to suppress fireflies
+fn clamp_contribution(light: vec3f, bounce: u32) -> vec3f {
+    let max_light = max(light.x, max(light.y, light.z));
+    if bounce == 0 || ctx.firefly_clamp <= 0.0 || max_light <= ctx.firefly_clamp { return light; }
+    return light * (ctx.firefly_clamp / max_light);
+}
+
 fn trace_ray(ray: Ray) -> Intersection {
-    let flags = 0x10 * (ctx.flags & 1);
+    return trace_ray_flags(ray, 0x10 * (ctx.flags & 1));
+}
+
+// For rays travelling inside a mesh, which would never find the way out if
+// back faces were culled
+fn trace_ray_inside(ray: Ray) -> Intersection {
+    return trace_ray_flags(ray, 0u);
+}
+
+// Docs for rayQuery functions: https://github.com/gfx-rs/wgpu/blob/trunk/etc/specs/ray_tracing.md
+fn trace_ray_flags(ray: Ray, flags: u32) -> Intersection {
     let ray_desc = RayDesc(flags, 0xFF, 0.001, 3.40282347e+38f, ray.pos, ray.dir);
 
     var rq: ray_query;
@@ -95,10 +257,24 @@ fn trace_ray(ray: Ray) -> Intersection {
     let position = v0.position * bary.x + v1.position * bary.y + v2.position * bary.z;
     let uv = v0.uv * bary.x + v1.uv * bary.y + v2.uv * bary.z;
 
+    // Solve for the direction along the triangle where only u changes,
+    // falling back to an edge when the UVs are degenerate
+    let edges = mat2x3f(v1.position - v0.position, v2.position - v0.position);
+    let duv = mat2x2f(v1.uv - v0.uv, v2.uv - v0.uv);
+    var tangent = edges[0];
+    if abs(determinant(duv)) > 1e-8 { tangent = edges * vec2(duv[1].y, -duv[0].y) / determinant(duv); }
+
+    // Normals transform with the inverse transpose, which is the cofactor
+    // matrix divided by the determinant. Only the sign of that matters here.
+    // Kept in sync with `Model::normal_transform`, which is tested on the CPU.
+    let linear = mat3x3f(intersection.object_to_world[0], intersection.object_to_world[1], intersection.object_to_world[2]);
+    let cofactor = mat3x3f(cross(linear[1], linear[2]), cross(linear[2], linear[0]), cross(linear[0], linear[1]));
+
     let transformed_position = (intersection.object_to_world * vec4f(position, 1.0)).xyz;
-    let transformed_normal = (intersection.object_to_world * vec4f(normal, 0.0)).xyz;
+    let transformed_normal = normalize(cofactor * normal) * sign(determinant(linear));
+    let transformed_tangent = (intersection.object_to_world * vec4f(tangent, 0.0)).xyz;
 
-    return Intersection(true, intersection.front_face, model.material, transformed_normal, transformed_position, uv);
+    return Intersection(true, intersection.front_face, materials[model.material], transformed_normal, transformed_tangent, transformed_position, uv, intersection.t, intersection.geometry_index);
 }
 
 fn schlick_approximation(cos_theta: f32, refractive_index: f32) -> f32 {
@@ -106,13 +282,3 @@ fn schlick_approximation(cos_theta: f32, refractive_index: f32) -> f32 {
     let rs = r * r;
     return rs + (1.0 - rs) * pow(1.0 - cos_theta, 5.0);
 }
-
-// From https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
-fn tone_map(x: vec3f) -> vec3f {
-    let a = 2.51;
-    let b = 0.03;
-    let c = 2.43;
-    let d = 0.59;
-    let e = 0.14;
-    return saturate((x * (a * x + b)) / (x * (c * x + d) + e));
-}
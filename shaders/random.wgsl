@@ -1,11 +1,94 @@
 var<private> seed: u32 = 0u;
 
+// Sobol state, the index is the pixel's sample number so progressive
+// rendering keeps walking along the same sequence between frames.
+var<private> sobol_seed: u32 = 0u;
+var<private> sobol_index: u32 = 0u;
+var<private> sobol_dimension: u32 = 0u;
+
+// Direction numbers for Sobol dimensions 1-3 from Joe & Kuo, dimension 0 is
+// just the bit reversed index.
+const SOBOL_DIRECTIONS: array<u32, 96> = array(
+    0x80000000u, 0xc0000000u, 0xa0000000u, 0xf0000000u, 0x88000000u, 0xcc000000u, 0xaa000000u, 0xff000000u,
+    0x80800000u, 0xc0c00000u, 0xa0a00000u, 0xf0f00000u, 0x88880000u, 0xcccc0000u, 0xaaaa0000u, 0xffff0000u,
+    0x80008000u, 0xc000c000u, 0xa000a000u, 0xf000f000u, 0x88008800u, 0xcc00cc00u, 0xaa00aa00u, 0xff00ff00u,
+    0x80808080u, 0xc0c0c0c0u, 0xa0a0a0a0u, 0xf0f0f0f0u, 0x88888888u, 0xccccccccu, 0xaaaaaaaau, 0xffffffffu,
+    0x80000000u, 0xc0000000u, 0x60000000u, 0x90000000u, 0xe8000000u, 0x5c000000u, 0x8e000000u, 0xc5000000u,
+    0x68800000u, 0x9cc00000u, 0xee600000u, 0x55900000u, 0x80680000u, 0xc09c0000u, 0x60ee0000u, 0x90550000u,
+    0xe8808000u, 0x5cc0c000u, 0x8e606000u, 0xc5909000u, 0x6868e800u, 0x9c9c5c00u, 0xeeee8e00u, 0x5555c500u,
+    0x8000e880u, 0xc0005cc0u, 0x60008e60u, 0x9000c590u, 0xe8006868u, 0x5c009c9cu, 0x8e00eeeeu, 0xc5005555u,
+    0x80000000u, 0xc0000000u, 0x20000000u, 0x50000000u, 0xf8000000u, 0x74000000u, 0xa2000000u, 0x93000000u,
+    0xd8800000u, 0x25400000u, 0x59e00000u, 0xe6d00000u, 0x78080000u, 0xb40c0000u, 0x82020000u, 0xc3050000u,
+    0x208f8000u, 0x51474000u, 0xfbea2000u, 0x75d93000u, 0xa0858800u, 0x914e5400u, 0xdbe79e00u, 0x25db6d00u,
+    0x58800080u, 0xe54000c0u, 0x79e00020u, 0xb6d00050u, 0x800800f8u, 0xc00c0074u, 0x200200a2u, 0x50050093u,
+);
+
 fn rand() -> f32 {
+    if (ctx.flags & 4) != 0 { return sobol_rand(); }
+
     seed = seed * 747796405u + 2891336453u;
     let f = f32(seed >> 9u) / f32(1u << 23u);
     return fract(f);
 }
 
+fn start_sample(pixel_idx: u32, sample_idx: u32) {
+    sobol_seed = hash(pixel_idx);
+    sobol_index = sample_idx;
+    sobol_dimension = 0u;
+}
+
+// Owen-scrambled Sobol from "Practical Hash-based Owen Scrambling" (Burley, 2020).
+// Dimensions are handed out in groups of four, each group using its own
+// shuffle of the sequence so consecutive groups are decorrelated.
+fn sobol_rand() -> f32 {
+    let group = sobol_dimension / 4u;
+    let dimension = sobol_dimension % 4u;
+    sobol_dimension++;
+
+    let group_seed = hash_combine(sobol_seed, hash(group));
+    let index = nested_uniform_scramble(sobol_index, group_seed);
+    let sample = nested_uniform_scramble(sobol(index, dimension), hash_combine(group_seed, dimension));
+    return f32(sample >> 8u) / 16777216.0;
+}
+
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0u { return reverseBits(index); }
+
+    var out = 0u;
+    for (var bit = 0u; bit < 32u; bit++) {
+        if ((index >> bit) & 1u) != 0u { out ^= SOBOL_DIRECTIONS[(dimension - 1u) * 32u + bit]; }
+    }
+    return out;
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    var out = x + seed;
+    out ^= out * 0x6c50b47cu;
+    out ^= out * 0xb82f1e52u;
+    out ^= out * 0xc7afe638u;
+    out ^= out * 0x8d22f6e6u;
+    return out;
+}
+
+fn hash_combine(seed: u32, value: u32) -> u32 {
+    return seed ^ (value + (seed << 6u) + (seed >> 2u));
+}
+
+// From https://nullprogram.com/blog/2018/07/31/
+fn hash(value: u32) -> u32 {
+    var x = value;
+    x ^= x >> 16u;
+    x *= 0x7feb352du;
+    x ^= x >> 15u;
+    x *= 0x846ca68bu;
+    x ^= x >> 16u;
+    return x;
+}
+
 fn rand_unit_vector() -> vec3f {
     var z = rand() * 2.0 - 1.0;
     var a = rand() * 2.0 * PI;
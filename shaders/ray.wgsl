@@ -11,7 +11,6 @@ fn ray_direction(pos: vec2f) -> vec3f {
 
 fn get_scattered_direction_metal(ray: Ray, trace: Intersection, material: MetalMaterial) -> ScatterResult {
     let is_specular = f32(rand() < material.specular_probability);
-    let smoothness = 1.0 - material.roughness;
 
     var normal = trace.normal;
     if material.normal_texture > 0 {
@@ -20,32 +19,103 @@ fn get_scattered_direction_metal(ray: Ray, trace: Intersection, material: MetalM
     }
 
     let diffuse = rand_cosine_hemisphere_vector(normal);
-    let specular = reflect(ray.dir, normal);
+    let microfacet = sample_ggx_normal(ray.dir, normal, trace.tangent, material.roughness, material.anisotropy, material.anisotropy_rotation);
+    var specular = reflect(ray.dir, microfacet);
+    if dot(specular, normal) <= 0.0 { specular = reflect(ray.dir, normal); }
 
     var diffuse_color = material.diffuse_color;
     if material.diffuse_texture > 0 { diffuse_color = sample_rgb(material.diffuse_texture - 1, trace.uv); }
 
+    var specular_color = material.specular_color;
+    if material.film_thickness > 0.0 {
+        let base = sqrt(material.specular_color);
+        specular_color = thin_film(dot(-ray.dir, normal), material.film_thickness, material.film_refractive_index, base);
+    }
+
     return ScatterResult(
-        mix(diffuse, specular, smoothness * is_specular),
-        mix(diffuse_color, material.specular_color, is_specular)
+        mix(diffuse, specular, is_specular),
+        mix(diffuse_color, specular_color, is_specular),
+        normal,
+        is_specular == 0.0
     );
 }
 
-fn get_scattered_direction_dielectric(ray: Ray, trace: Intersection, material: DielectricMaterial) -> vec3f {
+// Samples a microfacet normal from an anisotropic GGX distribution,
+// stretched along the (rotated) tangent direction. Falls back to the surface
+// normal for microfacets facing away from the ray.
+fn sample_ggx_normal(dir: vec3f, normal: vec3f, tangent: vec3f, roughness: f32, anisotropy: f32, rotation: f32) -> vec3f {
+    // Fitted so reflections spread about as far as when roughness blended
+    // the mirror direction toward a diffuse bounce, which scenes were tuned for
+    let alpha = max(roughness * (0.35 + 0.15 * roughness), 1e-4);
+    let aspect = sqrt(1.0 - 0.9 * abs(anisotropy));
+    var alpha_xy = vec2(alpha / aspect, alpha * aspect);
+    if anisotropy < 0.0 { alpha_xy = alpha_xy.yx; }
+
+    var t = tangent - normal * dot(normal, tangent);
+    if length(t) < 1e-6 { t = tangent_space(normal, vec3(1.0, 0.0, 0.0)); }
+    let angle = radians(rotation);
+    let x = normalize(t) * cos(angle) + cross(normal, normalize(t)) * sin(angle);
+    let y = cross(normal, x);
+
+    // Sample the slope of the unit roughness distribution and stretch it
+    let u = rand();
+    let phi = 2.0 * PI * rand();
+    let slope = sqrt(u / (1.0 - u)) * vec2(cos(phi), sin(phi)) * alpha_xy;
+    let microfacet = normalize(-slope.x * x - slope.y * y + normal);
+
+    if dot(-dir, microfacet) <= 0.0 { return normal; }
+    return microfacet;
+}
+
+// Reflectance of a thin dielectric film from the interference of light
+// reflected off its top and bottom interfaces. `base` is the amplitude
+// reflected by whatever is under the film.
+fn thin_film(cos_theta: f32, thickness: f32, eta: f32, base: vec3f) -> vec3f {
+    let wavelengths = vec3(650.0, 510.0, 475.0);
+
+    let sin_film = sqrt(max(1.0 - cos_theta * cos_theta, 0.0)) / eta;
+    let cos_film = sqrt(max(1.0 - sin_film * sin_film, 0.0));
+
+    let r12 = sqrt(schlick_approximation(cos_theta, eta)) * sign(1.0 - eta);
+    let r23 = base;
+    let phase = 4.0 * PI * eta * thickness * cos_film / wavelengths;
+
+    let interference = 2.0 * r12 * r23 * cos(phase);
+    return (r12 * r12 + r23 * r23 + interference) / (1.0 + r12 * r12 * r23 * r23 + interference);
+}
+
+// Picks between reflection and refraction by the average reflectance, with
+// the color making up for the difference between channels. Only a direction
+// is changed, so the result's diffuse flag is never set.
+fn get_scattered_direction_dielectric(ray: Ray, trace: Intersection, material: DielectricMaterial) -> ScatterResult {
     let normal = faceForward(trace.normal, trace.normal, ray.dir);
+    let microfacet = sample_ggx_normal(ray.dir, normal, trace.tangent, material.roughness, material.anisotropy, material.anisotropy_rotation);
 
     var refractive_index = material.refractive_index;
     if trace.front_face { refractive_index = 1.0 / material.refractive_index; }
 
-    let cos_theta = min(dot(-ray.dir, normal), 1.0);
+    let cos_theta = min(dot(-ray.dir, microfacet), 1.0);
     let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
 
-    let must_reflect = refractive_index * sin_theta > 1.0;
-    let reflect_prob = schlick_approximation(cos_theta, refractive_index);
-    let reflect = must_reflect || reflect_prob > rand();
+    // The film coats the outside, so only rays coming from outside see it
+    var reflectance = vec3(schlick_approximation(cos_theta, refractive_index));
+    if trace.front_face && material.film_thickness > 0.0 {
+        let eta = material.film_refractive_index;
+        let base = (eta - material.refractive_index) / (eta + material.refractive_index);
+        reflectance = thin_film(cos_theta, material.film_thickness, eta, vec3(base));
+    }
+    if refractive_index * sin_theta > 1.0 { reflectance = vec3(1.0); }
+
+    let probability = clamp(dot(reflectance, vec3(1.0 / 3.0)), 0.0, 1.0);
+    if rand() < probability {
+        var direction = reflect(ray.dir, microfacet);
+        if dot(direction, normal) <= 0.0 { direction = reflect(ray.dir, normal); }
+        return ScatterResult(direction, reflectance / probability, normal, false);
+    }
 
-    if reflect { return reflect(ray.dir, normal); }
-    else { return refract(ray.dir, normal, refractive_index); }
+    var direction = refract(ray.dir, microfacet, refractive_index);
+    if dot(direction, normal) >= 0.0 { direction = refract(ray.dir, normal, refractive_index); }
+    return ScatterResult(direction, (1.0 - reflectance) / (1.0 - probability), normal, false);
 }
 
 fn camera_direction() -> vec3f {
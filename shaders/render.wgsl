@@ -1,5 +1,11 @@
+#include "types.wgsl"
+
 @group(0) @binding(0) var<uniform> ctx: Uniform;
-@group(0) @binding(1) var<storage, read_write> accumulation: array<vec3f>;
+@group(0) @binding(1) var<storage, read_write> accumulation: array<Pixel>;
+@group(0) @binding(2) var<uniform> denoise: Denoise;
+@group(0) @binding(3) var<storage, read_write> denoise_a: array<vec3f>;
+@group(0) @binding(4) var<storage, read_write> denoise_b: array<vec3f>;
+@group(0) @binding(5) var<storage, read_write> aov: array<Aov>;
 
 // Vertex Shader //
 
@@ -23,5 +29,37 @@ fn frag(in: VertexOutput) -> @location(0) vec4<f32> {
     let pixel = vec2u(vec2f(in.uv.x, 1.0 - in.uv.y) * vec2f(ctx.window));
     let pixel_idx = pixel.y * ctx.window.x + pixel.x;
 
-    return vec4(accumulation[pixel_idx], 1.0);
+    var color = accumulation[pixel_idx].color;
+    if denoise.iterations > 0 {
+        if denoise.iterations % 2 == 1 { color = denoise_a[pixel_idx]; }
+        else { color = denoise_b[pixel_idx]; }
+    }
+
+    if selection_outline(pixel) { return vec4(1.0, 0.67, 0.0, 1.0); }
+    return vec4(tone_map(color * ctx.exposure), 1.0);
+}
+
+// If the pixel is just outside of the selected model
+fn selection_outline(pixel: vec2u) -> bool {
+    if ctx.selected == 0 || aov[pixel.y * ctx.window.x + pixel.x].model == ctx.selected { return false; }
+
+    for (var y = -2; y <= 2; y++) {
+        for (var x = -2; x <= 2; x++) {
+            let neighbor = vec2i(pixel) + vec2(x, y);
+            if any(neighbor < vec2(0)) || any(neighbor >= vec2i(ctx.window)) { continue; }
+            if aov[u32(neighbor.y) * ctx.window.x + u32(neighbor.x)].model == ctx.selected { return true; }
+        }
+    }
+
+    return false;
+}
+
+// From https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+fn tone_map(x: vec3f) -> vec3f {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return saturate((x * (a * x + b)) / (x * (c * x + d) + e));
 }
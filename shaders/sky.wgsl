@@ -0,0 +1,48 @@
+// Preetham sky, the coefficients are computed on the CPU in `sky.rs`.
+// The sun itself is not included, it is handled by `sample_sun` and `sun_disk`.
+fn physical_sky(dir: vec3f) -> vec3f {
+    if dir.y < 0.0 {
+        // Lambertian ground lit by the sun and a uniform approximation of the sky
+        let irradiance = ctx.sky.sun_irradiance * max(ctx.sky.sun_direction.y, 0.0)
+            + PI * sky_radiance(vec3(0.0, 1.0, 0.0));
+        return ctx.sky.ground_albedo * irradiance / PI;
+    }
+
+    return sky_radiance(dir);
+}
+
+fn sky_radiance(dir: vec3f) -> vec3f {
+    let p = ctx.sky.perez;
+    let cos_theta = max(dir.y, 0.001);
+    let cos_gamma = clamp(dot(dir, ctx.sky.sun_direction), -1.0, 1.0);
+    let gamma = acos(cos_gamma);
+
+    let perez = (1.0 + p[0] * exp(p[1] / cos_theta))
+        * (1.0 + p[2] * exp(p[3] * gamma) + p[4] * cos_gamma * cos_gamma);
+    return xyy_to_rgb(ctx.sky.zenith * perez / ctx.sky.perez_zenith);
+}
+
+// Radiance of the sun when looking straight at it, only used for paths that
+// did not sample it directly at their last hit.
+fn sun_disk(dir: vec3f) -> vec3f {
+    if (ctx.flags & 16) == 0 || dot(dir, ctx.sky.sun_direction) < ctx.sky.sun_cos_radius { return vec3(0.0); }
+
+    let solid_angle = 2.0 * PI * max(1.0 - ctx.sky.sun_cos_radius, 1e-6);
+    return ctx.sky.sun_irradiance / solid_angle;
+}
+
+fn sample_sun() -> LightSample {
+    let direction = sample_cone(ctx.sky.sun_direction, ctx.sky.sun_cos_radius);
+    return LightSample(direction, 3.40282347e+38f, ctx.sky.sun_irradiance);
+}
+
+fn xyy_to_rgb(yxy: vec3f) -> vec3f {
+    let scale = yxy.x / max(yxy.z, 1e-6);
+    let xyz = vec3(yxy.y * scale, yxy.x, (1.0 - yxy.y - yxy.z) * scale);
+    let xyz_to_srgb = mat3x3f(
+        vec3(3.2406, -0.9689, 0.0557),
+        vec3(-1.5372, 1.8758, -0.2040),
+        vec3(-0.4986, 0.0415, 1.0570)
+    );
+    return max(xyz_to_srgb * xyz, vec3(0.0));
+}
@@ -0,0 +1,35 @@
+const SUBSURFACE_STEPS: u32 = 256;
+
+// Random walk through the inside of a closed mesh, starting with a diffuse
+// transmission through the surface at `position`. Distances are sampled with
+// a randomly picked color channel and weighted by the average pdf over all
+// three, so each channel can have its own mean free path.
+fn subsurface_walk(position: vec3f, normal: vec3f, material: SubsurfaceMaterial) -> SubsurfaceResult {
+    let sigma_t = 1.0 / max(material.mean_free_path, vec3(1e-4));
+    let sigma_s = sigma_t * material.albedo;
+
+    var ray = Ray(position - normal * 0.0001, rand_cosine_hemisphere_vector(-normal));
+    var throughput = vec3(1.0);
+
+    for (var i = 0u; i < SUBSURFACE_STEPS; i++) {
+        let channel = min(u32(rand() * 3.0), 2u);
+        let distance = -log(1.0 - rand()) / sigma_t[channel];
+
+        let trace = trace_ray_inside(ray);
+        if !trace.hit { break; }
+
+        if distance >= trace.distance {
+            let transmittance = exp(-sigma_t * trace.distance);
+            throughput *= transmittance / dot(transmittance, vec3(1.0 / 3.0));
+
+            let normal = faceForward(trace.normal, trace.normal, -ray.dir);
+            return SubsurfaceResult(true, trace.position, normalize(normal), throughput);
+        }
+
+        let transmittance = exp(-sigma_t * distance);
+        throughput *= sigma_s * transmittance / dot(sigma_t * transmittance, vec3(1.0 / 3.0));
+        ray = Ray(ray.pos + ray.dir * distance, rand_unit_vector());
+    }
+
+    return SubsurfaceResult(false, vec3(0.0), vec3(0.0), vec3(0.0));
+}
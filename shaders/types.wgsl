@@ -1,31 +1,6 @@
-struct Uniform {
-    window: vec2u,
-    camera: Camera,
-    frame: u32,
-    accumulation_frame: u32,
-    flags: u32,
-
-    exposure: f32,
-    enviroment: f32,
-    max_bounces: u32,
-    samples: u32,
-}
-
-struct Camera {
-    pos: vec3f,
-    pitch: f32,
-    yaw: f32,
-
-    fov: f32,
-    aspect: f32,
-}
-
-struct Material {
-    tag: u32,
-    metal: MetalMaterial,
-    dielectric: DielectricMaterial
-}
+#include "structs.wgsl"
 
+// Unpacked views of `Material`, packed by `Material::to_gpu`
 struct MetalMaterial {
     diffuse_color: vec3f,
     specular_color: vec3f,
@@ -37,23 +12,30 @@ struct MetalMaterial {
     emission_strength: f32,
 
     diffuse_texture: u32,
-    normal_texture: u32
+    normal_texture: u32,
+
+    anisotropy: f32,
+    anisotropy_rotation: f32,
+
+    film_thickness: f32,
+    film_refractive_index: f32
 }
 
 struct DielectricMaterial {
     refractive_index: f32,
-}
+    roughness: f32,
 
-struct Model {
-    material: Material,
-    vertex_start: u32,
-    index_start: u32,
+    anisotropy: f32,
+    anisotropy_rotation: f32,
+
+    film_thickness: f32,
+    film_refractive_index: f32
 }
 
-struct Vertex {
-    position: vec3f,
-    normal: vec3f,
-    uv: vec2f
+struct SubsurfaceMaterial {
+    albedo: vec3f,
+    mean_free_path: vec3f,
+    refractive_index: f32,
 }
 
 struct Ray {
@@ -63,7 +45,22 @@ struct Ray {
 
 struct ScatterResult {
     direction: vec3f,
-    color: vec3f
+    color: vec3f,
+    normal: vec3f,
+    diffuse: bool
+}
+
+struct SubsurfaceResult {
+    exited: bool,
+    position: vec3f,
+    normal: vec3f,
+    throughput: vec3f,
+}
+
+struct LightSample {
+    direction: vec3f,
+    distance: f32,
+    radiance: vec3f,
 }
 
 struct Intersection {
@@ -71,17 +68,43 @@ struct Intersection {
     front_face: bool,
     material: Material,
     normal: vec3f,
+    // Direction of increasing u, used for anisotropic materials
+    tangent: vec3f,
     position: vec3f,
-    uv: vec2f
+    uv: vec2f,
+    distance: f32,
+    model: u32
 }
 
 fn intersection_miss() -> Intersection {
-    return Intersection(false, true, default_material(), vec3f(0.0), vec3f(0.0), vec2f(0.0));
+    return Intersection(false, true, default_material(), vec3f(0.0), vec3f(0.0), vec3f(0.0), vec2f(0.0), 3.40282347e+38f, 0);
 }
 
 fn default_material() -> Material {
-    return Material(0,
-        MetalMaterial(vec3(1.0), vec3(0.0), 0.0, 0.0, vec3(0.0), 0.0, 0, 0),
-        DielectricMaterial(1.0)
+    return Material(0, vec2(0u), array<vec4f, 4>());
+}
+
+// Unpacking for the parameter blocks filled in by `Material::to_gpu`
+fn metal_material(material: Material) -> MetalMaterial {
+    let p = material.params;
+    return MetalMaterial(
+        p[0].xyz, p[1].xyz, p[0].w, p[1].w, p[2].xyz, p[2].w,
+        material.textures.x, material.textures.y,
+        p[3].x, p[3].y, p[3].z, p[3].w
     );
 }
+
+fn dielectric_material(material: Material) -> DielectricMaterial {
+    let p = material.params;
+    return DielectricMaterial(p[0].x, p[0].y, p[0].z, p[0].w, p[1].x, p[1].y);
+}
+
+fn volume_material(material: Material) -> VolumeMaterial {
+    let p = material.params;
+    return VolumeMaterial(p[0].w, p[0].xyz, p[1].x);
+}
+
+fn subsurface_material(material: Material) -> SubsurfaceMaterial {
+    let p = material.params;
+    return SubsurfaceMaterial(p[0].xyz, p[1].xyz, p[0].w);
+}
@@ -0,0 +1,45 @@
+// Distance along the ray to the next scattering event in the medium, or
+// `max_distance` if the ray makes it through. With a grey extinction
+// coefficient the transmittance cancels out with the sampling pdf.
+fn free_flight(ray: Ray, max_distance: f32, medium: VolumeMaterial, height: f32) -> f32 {
+    if medium.density <= 0.0 { return max_distance; }
+
+    let span = medium_span(ray, height);
+    let distance = span.x - log(1.0 - rand()) / medium.density;
+    if distance < min(span.y, max_distance) { return distance; }
+    return max_distance;
+}
+
+fn fog_optical_depth(ray: Ray, distance: f32) -> f32 {
+    let span = medium_span(ray, ctx.fog.height);
+    return ctx.fog.medium.density * max(min(span.y, distance) - span.x, 0.0);
+}
+
+// Start and end distance of the part of the ray below `height`
+fn medium_span(ray: Ray, height: f32) -> vec2f {
+    let far = 3.40282347e+38f;
+    if ray.dir.y == 0.0 { return select(vec2(0.0), vec2(0.0, far), ray.pos.y < height); }
+
+    let t = (height - ray.pos.y) / ray.dir.y;
+    if ray.dir.y > 0.0 { return vec2(0.0, max(t, 0.0)); }
+    return vec2(max(t, 0.0), far);
+}
+
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * denom * sqrt(denom));
+}
+
+// Samples a scattered direction proportional to the phase function, so the
+// path throughput is only scaled by the albedo.
+fn sample_henyey_greenstein(dir: vec3f, g: f32) -> vec3f {
+    var cos_theta = 1.0 - 2.0 * rand();
+    if abs(g) > 0.001 {
+        let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * rand());
+        cos_theta = (1.0 + g * g - s * s) / (2.0 * g);
+    }
+
+    let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    let phi = 2.0 * PI * rand();
+    return tangent_space(dir, vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta));
+}
@@ -1,38 +1,111 @@
-use std::time::Instant;
+use std::{
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Result;
 
 use compute::{
-    bindings::{acceleration_structure::AccelerationStructure, StorageBuffer, UniformBuffer},
+    bindings::{StorageBuffer, UniformBuffer},
     export::{
         egui::Context,
-        nalgebra::{Matrix4, Vector2, Vector3},
-        wgpu::RenderPass,
+        nalgebra::{Vector2, Vector3},
+        wgpu::{RenderPass, ShaderStages},
     },
+    gpu::Gpu,
     interactive::{GraphicsCtx, Interactive},
     misc::mutability::Mutable,
     pipeline::{compute::ComputePipeline, render::RenderPipeline},
 };
+use image::{codecs::png::PngEncoder, ExtendedColorType, ImageEncoder, RgbaImage};
 
 use crate::{
-    types::{Model, ModelBuffer, TransformBuffer, Uniform, Vertex},
+    assets::AssetWatcher,
+    camera::Camera,
+    consts::MAX_DENOISE_ITERATIONS,
+    convergence::Convergence,
+    gizmo::Gizmo,
+    history::{state_hash, History, Snapshot},
+    loader::{LoadMode, SceneLoader},
+    misc::tone_map,
+    scene::{
+        build_acceleration, gpu_lights, gpu_materials, gpu_models, Scene, SceneBuffers, SourceFile,
+    },
+    shaders::Shaders,
+    sky::SkySettings,
+    tiles::Tiles,
+    types::{
+        Aov, Denoise, EulerOrder, Flags, Light, Mesh, Model, NamedMaterial, Pixel, ShaderFeatures,
+        Uniform, Vertex,
+    },
     ui::ui,
 };
 
 pub struct App {
     pub compute_pipeline: ComputePipeline,
+    /// One per denoise iteration, each bound to its uniform in
+    /// [`App::denoise_passes`].
+    pub denoise_pipelines: Vec<ComputePipeline>,
     pub render_pipeline: RenderPipeline,
-    pub accumulation_buffer: StorageBuffer<Vec<Vector3<f32>>, Mutable>,
+    pub shaders: Shaders,
+    pub accumulation_buffer: StorageBuffer<Vec<Pixel>, Mutable>,
+    pub aov_buffer: StorageBuffer<Vec<Aov>, Mutable>,
 
     pub uniform: Uniform,
     pub uniform_buffer: UniformBuffer<Uniform>,
 
+    pub denoise: Denoise,
+    /// Read by the render pipeline to pick the denoised output.
+    pub denoise_buffer: UniformBuffer<Denoise>,
+    /// The settings of each denoise iteration.
+    pub denoise_passes: Vec<UniformBuffer<Denoise>>,
+    pub denoise_buffers: [StorageBuffer<Vec<Vector3<f32>>, Mutable>; 2],
+    pub denoise_enabled: bool,
+    /// Noise in percent of the mean luminance below which the image is
+    /// considered converged and the denoiser is skipped.
+    pub denoise_cutoff: f32,
+    pub convergence: Convergence,
+    pub tiles: Tiles,
+    pub sky: SkySettings,
+    pub tile_buffer: StorageBuffer<Vec<Vector2<u32>>, Mutable>,
+
     pub models: Vec<Model>,
-    pub acceleration_structure: AccelerationStructure<Vertex>,
-    pub model_buffer: ModelBuffer,
-    pub transform_buffer: TransformBuffer,
+    pub materials: Vec<NamedMaterial>,
+    /// Path of the material library file for import and export.
+    pub library_path: String,
+    pub library_error: Option<String>,
+    pub lights: Vec<Light>,
+    pub meshes: Vec<Mesh>,
+    /// CPU copies of the geometry and textures, kept around so the scene
+    /// buffers can be rebuilt when files are imported.
+    pub verts: Vec<Vertex>,
+    pub index: Vec<u32>,
+    pub textures: Vec<Arc<RgbaImage>>,
+    pub buffers: SceneBuffers,
+    pub gpu: Gpu,
+    /// Scenes being loaded in the background.
+    pub loading: Vec<SceneLoader>,
+    pub sources: Vec<SourceFile>,
+    pub assets: AssetWatcher,
+    /// Path of the OBJ, glTF or scene file to import.
+    pub import_path: String,
+    pub import_error: Option<String>,
 
     pub last_frame: Instant,
     pub last_invaladation: Instant,
     pub last_window: Vector2<u32>,
+    /// Cursor position in normalized screen coordinates.
+    pub cursor: Option<Vector2<f32>>,
+    /// Index of the model selected by clicking on it.
+    pub selected: Option<usize>,
+    pub gizmo: Gizmo,
+    pub history: History,
+    /// Order rotations are shown in, see [`EulerOrder`].
+    pub euler_order: EulerOrder,
+    /// Result of the last pick, see [`App::pick`].
+    pub picked: Arc<Mutex<Option<u32>>>,
     pub accumulate: bool,
     pub screen_fraction: u8,
 }
@@ -41,25 +114,416 @@ impl App {
     pub fn invalidate_accumulation(&mut self) {
         self.last_invaladation = Instant::now();
         self.uniform.accumulation_frame = 0;
+        self.convergence.reset();
+        self.tiles.reset();
+    }
+
+    /// With adaptive sampling every pixel can have a different sample count,
+    /// so the mean from the last downloaded stats is used instead.
+    pub fn samples_per_pixel(&self) -> u32 {
+        let flags = Flags::from_bits_truncate(self.uniform.flags);
+        if flags.contains(Flags::ADAPTIVE_SAMPLING) {
+            self.convergence.stats().map_or(0, |x| x.samples as u32)
+        } else {
+            self.uniform.accumulation_frame * self.uniform.samples
+        }
+    }
+
+    pub fn capture(&self) {
+        let (window, exposure) = (self.last_window, self.uniform.exposure);
+        self.accumulation_buffer.download_async(move |data| {
+            let encoder = PngEncoder::new(File::create("out.png").unwrap());
+            let data = data
+                .iter()
+                .map(|x| (tone_map(x.color * exposure) * 255.0).map(|x| x as u8))
+                .flat_map(|x| [x.x, x.y, x.z])
+                .collect::<Vec<_>>();
+
+            encoder
+                .write_image(&data, window.x, window.y, ExtendedColorType::Rgb8)
+                .unwrap();
+        });
+    }
+
+    /// Reads back the model under `cursor` (in normalized screen coordinates)
+    /// from the AOV buffer. The model index plus one ends up in `picked`.
+    pub fn pick(&self, cursor: Vector2<f32>) {
+        let window = self.last_window;
+        let pixel = cursor
+            .component_mul(&window.cast())
+            .map(|x| x as u32)
+            .zip_map(&window, |x, size| x.min(size.saturating_sub(1)));
+        let index = (pixel.y * window.x + pixel.x) as usize;
+
+        let picked = self.picked.clone();
+        self.aov_buffer.download_async(move |aov| {
+            *picked.lock().unwrap() = Some(aov.get(index).map_or(0, |x| x.model));
+        });
     }
 
     pub fn upload_models(&self) {
-        let gpu_models = self.models.iter().map(|x| x.to_gpu()).collect::<Vec<_>>();
-        self.model_buffer.upload_shrink(&gpu_models).unwrap();
+        let gpu_models = gpu_models(&self.models, &self.meshes);
+        self.buffers.models.upload_shrink(&gpu_models).unwrap();
 
         let transformations = self
             .models
             .iter()
-            .map(|model| {
-                let transformation = Matrix4::new_nonuniform_scaling(&model.scale)
-                    * Matrix4::new_rotation(model.rotation)
-                    * Matrix4::new_translation(&model.position);
-                transformation.remove_row(3).transpose()
-            })
+            .map(|model| model.transform().remove_row(3).transpose())
             .collect::<Vec<_>>();
-        self.transform_buffer.upload(&transformations).unwrap();
-        self.acceleration_structure.update();
+        self.buffers
+            .transformation
+            .upload(&transformations)
+            .unwrap();
+        self.buffers.acceleration.update();
+    }
+
+    /// Needed whenever models are added or removed, as the acceleration
+    /// structure has a primitive per model.
+    pub fn rebuild_acceleration(&mut self) {
+        let (transformation, acceleration) = build_acceleration(
+            &self.gpu,
+            &self.buffers.vertex,
+            &self.buffers.index,
+            &self.meshes,
+            &self.models,
+        )
+        .unwrap();
+        self.buffers.transformation = transformation;
+        self.buffers.acceleration = acceleration;
+        self.rebind();
     }
+
+    /// Starts loading an OBJ, glTF or scene file in the background, it's
+    /// added to the scene by [`App::poll_loading`] once ready.
+    pub fn import(&mut self, path: impl AsRef<Path>) {
+        self.loading
+            .push(SceneLoader::spawn(path, LoadMode::Append));
+    }
+
+    /// Starts reloading any OBJ files that changed on disk.
+    pub fn poll_assets(&mut self) {
+        let loading = self
+            .loading
+            .iter()
+            .map(|x| x.path.as_path())
+            .collect::<Vec<_>>();
+        for path in self.assets.poll(&self.sources, &loading) {
+            self.loading
+                .push(SceneLoader::spawn(path, LoadMode::Reload));
+        }
+    }
+
+    /// Adds finished scenes from [`App::loading`]. Everything is uploaded
+    /// again as the vertex and texture buffers can't grow in place.
+    pub fn poll_loading(&mut self) {
+        let mut i = 0;
+        while i < self.loading.len() {
+            if !self.loading[i].finished() {
+                i += 1;
+                continue;
+            }
+
+            let loader = self.loading.remove(i);
+            let (path, mode) = (loader.path.clone(), loader.mode);
+            let loaded = match loader.join() {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    self.import_error = Some(format!("{path:?}: {err}"));
+                    continue;
+                }
+            };
+
+            let selected = self.selected.map(|x| self.models[x].id);
+            let (mut assets, mut remap) = (None, None);
+            let scene = match mode {
+                LoadMode::Replace => {
+                    let mut watcher = AssetWatcher::default();
+                    watcher.track(&loaded);
+                    assets = Some(watcher);
+                    loaded
+                }
+                LoadMode::Append => {
+                    self.assets.track(&loaded);
+                    let mut scene = self.scene();
+                    scene.append(loaded);
+                    scene
+                }
+                LoadMode::Reload => {
+                    let mut scene = self.scene();
+                    remap = Some(self.assets.reload(&mut scene, &path, loaded));
+                    scene
+                }
+            };
+
+            if let Err(err) = self.rebuild_scene(scene) {
+                self.import_error = Some(format!("{path:?}: {err}"));
+                continue;
+            }
+
+            // The old scene's history is only dropped once the new one is in use
+            if let Some(assets) = assets {
+                self.history.clear();
+                self.assets = assets;
+            }
+            if let Some(remap) = remap {
+                self.history.remap(&remap, &self.models, &self.materials);
+            }
+            self.selected = selected.and_then(|id| self.models.iter().position(|x| x.id == id));
+            self.import_error = None;
+        }
+    }
+
+    /// Records an undo step if the models, materials, camera or settings
+    /// changed, see [`History::record`].
+    pub fn record_history(&mut self, ctx: &Context) {
+        let hash = state_hash(&self.models, &self.materials, &self.uniform);
+        self.history.record(ctx, hash, || Snapshot {
+            models: self.models.clone(),
+            materials: self.materials.clone(),
+            uniform: self.uniform.clone(),
+            sky: self.sky.clone(),
+        });
+    }
+
+    pub fn undo(&mut self, steps: usize) {
+        if let Some(snapshot) = self.history.undo(steps) {
+            self.restore(snapshot);
+        }
+    }
+
+    pub fn redo(&mut self, steps: usize) {
+        if let Some(snapshot) = self.history.redo(steps) {
+            self.restore(snapshot);
+        }
+    }
+
+    /// Frame counters and the window size are kept, as they aren't edits.
+    fn restore(&mut self, snapshot: Snapshot) {
+        // The acceleration structure holds each model's mesh range, so it has
+        // to be rebuilt if any of them point at a different mesh
+        let rebuild = snapshot.models.len() != self.models.len()
+            || (snapshot.models.iter().zip(&self.models)).any(|(a, b)| a.mesh != b.mesh);
+        self.models = snapshot.models;
+        self.materials = snapshot.materials;
+        self.sky = snapshot.sky;
+        self.uniform = Uniform {
+            window: self.uniform.window,
+            frame: self.uniform.frame,
+            accumulation_frame: self.uniform.accumulation_frame,
+            selected: self.uniform.selected,
+            camera: Camera {
+                aspect: self.uniform.camera.aspect,
+                ..snapshot.uniform.camera
+            },
+            ..snapshot.uniform
+        };
+
+        self.selected = self.selected.filter(|&x| x < self.models.len());
+        self.upload_materials();
+        if rebuild {
+            self.rebuild_acceleration();
+        } else {
+            self.upload_models();
+            self.invalidate_accumulation();
+        }
+    }
+
+    /// Copies the model's mesh so it can later be edited on its own, unlike
+    /// [`Model::instance`].
+    pub fn duplicate_model(&mut self, index: usize) -> Result<()> {
+        let mut scene = self.scene();
+        let mesh = scene.copy_mesh(scene.models[index].mesh);
+        let original = &scene.models[index];
+        scene.models.push(Model {
+            name: format!("{} Copy", original.name),
+            mesh,
+            ..original.instance()
+        });
+        self.rebuild_scene(scene)
+    }
+
+    /// The mesh is left in the vertex buffer, in case other models use it.
+    pub fn remove_model(&mut self, index: usize) {
+        self.models.remove(index);
+        self.selected = match self.selected {
+            Some(selected) if selected == index => None,
+            Some(selected) if selected > index => Some(selected - 1),
+            selected => selected,
+        };
+        self.rebuild_acceleration();
+    }
+
+    /// A copy of the scene to be edited and passed to [`App::rebuild_scene`],
+    /// so the current one is untouched if that fails.
+    fn scene(&self) -> Scene {
+        Scene {
+            meshes: self.meshes.clone(),
+            models: self.models.clone(),
+            materials: self.materials.clone(),
+            lights: self.lights.clone(),
+            textures: self.textures.clone(),
+
+            verts: self.verts.clone(),
+            index: self.index.clone(),
+            sources: self.sources.clone(),
+        }
+    }
+
+    /// Uploads a new scene and switches to it. If the buffers can't be
+    /// created the current scene is kept.
+    fn rebuild_scene(&mut self, scene: Scene) -> Result<()> {
+        self.buffers = scene.finish(&self.gpu)?;
+
+        self.meshes = scene.meshes;
+        self.models = scene.models;
+        self.materials = scene.materials;
+        self.lights = scene.lights;
+        self.textures = scene.textures;
+        self.verts = scene.verts;
+        self.index = scene.index;
+        self.sources = scene.sources;
+
+        self.rebind();
+        Ok(())
+    }
+
+    /// The scene buffers are bound directly, so the compute pipeline has to be
+    /// recreated whenever they are.
+    fn rebind(&mut self) {
+        self.compute_pipeline = compute_pipeline(
+            &self.gpu,
+            &self.shaders,
+            &self.uniform_buffer,
+            &self.accumulation_buffer,
+            &self.aov_buffer,
+            &self.tile_buffer,
+            &self.buffers,
+        );
+        self.upload_models();
+        self.invalidate_accumulation();
+    }
+
+    /// Recreates the pipelines if the shaders changed on disk. If they fail to
+    /// compile the last working pipelines are kept.
+    pub fn reload_shaders(&mut self) {
+        if self.shaders.poll() {
+            self.recreate_pipelines();
+        }
+    }
+
+    /// Compiles the shaders with a different set of features.
+    pub fn set_shader_features(&mut self, features: ShaderFeatures) {
+        self.shaders.features = features;
+        if self.shaders.rebuild() {
+            self.recreate_pipelines();
+        }
+    }
+
+    fn recreate_pipelines(&mut self) {
+        let [denoise_a, denoise_b] = &self.denoise_buffers;
+        self.denoise_pipelines = self
+            .denoise_passes
+            .iter()
+            .map(|pass| {
+                denoise_pipeline(
+                    &self.gpu,
+                    &self.shaders,
+                    &self.uniform_buffer,
+                    pass,
+                    &self.accumulation_buffer,
+                    &self.aov_buffer,
+                    [denoise_a, denoise_b],
+                )
+            })
+            .collect();
+        self.render_pipeline = render_pipeline(
+            &self.gpu,
+            &self.shaders,
+            &self.uniform_buffer,
+            &self.denoise_buffer,
+            &self.accumulation_buffer,
+            &self.aov_buffer,
+            [denoise_a, denoise_b],
+        );
+        self.rebind();
+    }
+
+    pub fn upload_materials(&self) {
+        self.buffers
+            .materials
+            .upload_shrink(&gpu_materials(&self.materials))
+            .unwrap();
+    }
+
+    pub fn upload_lights(&self) {
+        self.buffers
+            .lights
+            .upload_shrink(&gpu_lights(&self.lights))
+            .unwrap();
+    }
+}
+
+pub fn compute_pipeline(
+    gpu: &Gpu,
+    shaders: &Shaders,
+    uniform: &UniformBuffer<Uniform>,
+    accumulation: &StorageBuffer<Vec<Pixel>, Mutable>,
+    aov: &StorageBuffer<Vec<Aov>, Mutable>,
+    tiles: &StorageBuffer<Vec<Vector2<u32>>, Mutable>,
+    buffers: &SceneBuffers,
+) -> ComputePipeline {
+    gpu.compute_pipeline(shaders.compute.clone())
+        .bind(uniform)
+        .bind(accumulation)
+        .bind(&buffers.models)
+        .bind(&buffers.acceleration)
+        .bind(&buffers.vertex)
+        .bind(&buffers.index)
+        .bind(&gpu.create_sampler())
+        .bind(&buffers.textures)
+        .bind(aov)
+        .bind(tiles)
+        .bind(&buffers.lights)
+        .bind(&buffers.materials)
+        .finish()
+}
+
+pub fn denoise_pipeline(
+    gpu: &Gpu,
+    shaders: &Shaders,
+    uniform: &UniformBuffer<Uniform>,
+    denoise: &UniformBuffer<Denoise>,
+    accumulation: &StorageBuffer<Vec<Pixel>, Mutable>,
+    aov: &StorageBuffer<Vec<Aov>, Mutable>,
+    [denoise_a, denoise_b]: [&StorageBuffer<Vec<Vector3<f32>>, Mutable>; 2],
+) -> ComputePipeline {
+    gpu.compute_pipeline(shaders.denoise.clone())
+        .bind(uniform)
+        .bind(denoise)
+        .bind(accumulation)
+        .bind(aov)
+        .bind(denoise_a)
+        .bind(denoise_b)
+        .finish()
+}
+
+pub fn render_pipeline(
+    gpu: &Gpu,
+    shaders: &Shaders,
+    uniform: &UniformBuffer<Uniform>,
+    denoise: &UniformBuffer<Denoise>,
+    accumulation: &StorageBuffer<Vec<Pixel>, Mutable>,
+    aov: &StorageBuffer<Vec<Aov>, Mutable>,
+    [denoise_a, denoise_b]: [&StorageBuffer<Vec<Vector3<f32>>, Mutable>; 2],
+) -> RenderPipeline {
+    gpu.render_pipeline(shaders.render.clone())
+        .bind(uniform, ShaderStages::FRAGMENT)
+        .bind(accumulation, ShaderStages::FRAGMENT)
+        .bind(denoise, ShaderStages::FRAGMENT)
+        .bind(denoise_a, ShaderStages::FRAGMENT)
+        .bind(denoise_b, ShaderStages::FRAGMENT)
+        .bind(aov, ShaderStages::FRAGMENT)
+        .finish()
 }
 
 impl Interactive for App {
@@ -75,7 +539,6 @@ impl Interactive for App {
         let screen_fraction =
             1.0 + (4.0 - 1.0) * (1.0 - self.last_invaladation.elapsed().as_secs_f32().min(1.0));
 
-        self.uniform.accumulation_frame += 1;
         if !self.accumulate {
             self.uniform.accumulation_frame = 0;
         }
@@ -86,18 +549,81 @@ impl Interactive for App {
 
         if self.last_window != window {
             self.uniform.accumulation_frame = 0;
+            self.convergence.reset();
+            self.tiles.reset();
             self.last_window = window;
+
+            let pixels = (window.x * window.y) as usize;
             self.accumulation_buffer
-                .upload_shrink(&vec![Vector3::zeros(); (window.x * window.y) as usize])
+                .upload_shrink(&vec![Pixel::default(); pixels])
+                .unwrap();
+            self.aov_buffer
+                .upload_shrink(&vec![Aov::default(); pixels])
                 .unwrap();
+            for buffer in self.denoise_buffers.iter() {
+                buffer
+                    .upload_shrink(&vec![Vector3::zeros(); pixels])
+                    .unwrap();
+            }
+        }
+
+        let samples = self.samples_per_pixel();
+        let elapsed = self.last_invaladation.elapsed();
+        if self.convergence.check(samples, elapsed) && self.convergence.auto_capture {
+            self.capture();
         }
 
         self.uniform.window = window;
-        self.uniform.frame += 1;
-        self.uniform_buffer.upload(&self.uniform).unwrap();
+        if !self.convergence.stopped {
+            self.uniform.frame += 1;
+            self.uniform_buffer.upload(&self.uniform).unwrap();
+
+            let dispatch = self.tiles.next(window, self.cursor);
+            self.tile_buffer.upload_shrink(&dispatch.tiles).unwrap();
+            self.compute_pipeline.dispatch(Vector3::new(
+                dispatch.extent.x.div_ceil(8),
+                dispatch.extent.y.div_ceil(8),
+                dispatch.tiles.len() as u32,
+            ));
+
+            if dispatch.finished {
+                self.uniform.accumulation_frame += 1;
+            }
+
+            if let Some(callback) = self.convergence.request_stats() {
+                self.accumulation_buffer.download_async(callback);
+            }
+        } else {
+            self.uniform_buffer.upload(&self.uniform).unwrap();
+        }
+
+        let workgroups = Vector3::new(window.x.div_ceil(8), window.y.div_ceil(8), 1);
+        // The denoiser's edge stopping weights come from the AOVs
+        let aovs = self.shaders.features.contains(ShaderFeatures::AOVS);
+        let iterations =
+            if self.denoise_enabled && aovs && !self.convergence.converged(self.denoise_cutoff) {
+                self.denoise.iterations.min(MAX_DENOISE_ITERATIONS)
+            } else {
+                0
+            };
+
+        let passes = self.denoise_passes.iter().zip(&self.denoise_pipelines);
+        for (iteration, (pass, pipeline)) in passes.take(iterations as usize).enumerate() {
+            pass.upload(&Denoise {
+                iteration: iteration as u32,
+                ..self.denoise
+            })
+            .unwrap();
+            pipeline.dispatch(workgroups);
+        }
+
+        self.denoise_buffer
+            .upload(&Denoise {
+                iterations,
+                ..self.denoise
+            })
+            .unwrap();
 
         self.render_pipeline.draw_quad(render_pass, 0..1);
-        self.compute_pipeline
-            .dispatch(Vector3::new(window.x.div_ceil(8), window.y.div_ceil(8), 1));
     }
 }
@@ -0,0 +1,262 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    scene::{Remap, Scene, SourceFile},
+    scene_file::SceneFile,
+    types::{Material, Mesh, Model},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches the files the scene was loaded from, so OBJ and glTF files can be
+/// reloaded when they or their materials and textures change.
+pub struct AssetWatcher {
+    modified: HashMap<PathBuf, Option<SystemTime>>,
+    /// Materials as they were last loaded by id, to tell which have been
+    /// edited in the UI. Texture ids are cleared as they change on reload.
+    loaded: HashMap<u32, Material>,
+    last_poll: Instant,
+}
+
+impl AssetWatcher {
+    /// Remembers the materials of a newly loaded scene.
+    pub fn track(&mut self, scene: &Scene) {
+        for material in scene.materials.iter() {
+            self.loaded
+                .insert(material.id, without_textures(material.material));
+        }
+    }
+
+    /// Returns the OBJ files that changed, or whose materials or textures
+    /// changed, since the last poll or since they were loaded. Changes to OBJ
+    /// files in `loading` are held back until they finish loading, as
+    /// exporters often write a file in several steps.
+    pub fn poll(&mut self, sources: &[SourceFile], loading: &[&Path]) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let mut modified = HashMap::new();
+        for source in sources {
+            let time = || fs::metadata(&source.path).and_then(|x| x.modified()).ok();
+            modified.entry(source.path.clone()).or_insert_with(time);
+        }
+
+        // Files seen for the first time are compared to their time when they
+        // were loaded. A file can be used by more than one OBJ, such as a
+        // shared texture.
+        let mut changed = Vec::new();
+        for source in sources {
+            let Some(obj) = &source.obj else {
+                continue;
+            };
+            let previous = (self.modified.get(&source.path).copied()).unwrap_or(source.modified);
+            if loading.contains(&obj.as_path()) {
+                // Keep the old time so the change is picked up once it's loaded
+                modified.insert(source.path.clone(), previous);
+            } else if previous != modified[&source.path] && !changed.contains(obj) {
+                changed.push(obj.clone());
+            }
+        }
+
+        self.modified = modified;
+        changed
+    }
+
+    /// Replaces the models previously loaded from the OBJ at `path` with the
+    /// ones in `loaded`. Models and materials are matched by name, so models
+    /// keep their transforms and materials keep any edits made since they
+    /// were loaded. New models take the transform of the OBJ's entry in the
+    /// scene file, if any. The returned remap sends each of the old meshes to
+    /// the one that replaced it.
+    pub fn reload(&mut self, scene: &mut Scene, path: &Path, loaded: Scene) -> Remap {
+        let source = Some(path.to_owned());
+        let old_models = (0..scene.models.len())
+            .filter(|&i| scene.models[i].source == source)
+            .collect::<Vec<_>>();
+        let mut old_materials = old_models
+            .iter()
+            .map(|&i| scene.models[i].material)
+            .collect::<Vec<_>>();
+        old_materials.sort_unstable();
+        old_materials.dedup();
+
+        // The old textures and meshes are dropped by `compact` once unused
+        let texture_offset = scene.textures.len() as u32;
+        scene.textures.extend(loaded.textures);
+
+        let mut materials = Vec::new();
+        for mut material in loaded.materials {
+            for texture in material.material.textures_mut() {
+                if *texture != 0 {
+                    *texture += texture_offset;
+                }
+            }
+
+            let existing = (old_materials.iter())
+                .find(|&&i| scene.materials[i as usize].name == material.name);
+            let Some(&index) = existing else {
+                self.loaded
+                    .insert(material.id, without_textures(material.material));
+                materials.push(scene.materials.len() as u32);
+                scene.materials.push(material);
+                continue;
+            };
+
+            let current = &mut scene.materials[index as usize];
+            let edited = (self.loaded.get(&current.id))
+                .is_some_and(|x| *x != without_textures(current.material));
+            if edited {
+                // Textures aren't edited in the UI, so they always follow the file
+                let textures = material.material.textures_mut();
+                for (texture, new) in current.material.textures_mut().into_iter().zip(textures) {
+                    *texture = *new;
+                }
+            } else {
+                current.material = material.material;
+            }
+
+            self.loaded
+                .insert(current.id, without_textures(material.material));
+            materials.push(index);
+        }
+
+        let (vertex_offset, index_offset) = (scene.verts.len() as u32, scene.index.len() as u32);
+        let mesh_offset = scene.meshes.len() as u32;
+        let mut replaced = (0..mesh_offset).collect::<Vec<_>>();
+        scene
+            .meshes
+            .extend(loaded.meshes.into_iter().map(|mesh| Mesh {
+                first_vertex: mesh.first_vertex + vertex_offset,
+                first_index: mesh.first_index + index_offset,
+                ..mesh
+            }));
+        scene.verts.extend(loaded.verts);
+        scene.index.extend(loaded.index);
+
+        // New models get the transform the scene file gives to the OBJ
+        let config = (scene.sources.iter())
+            .filter(|x| x.obj.is_none())
+            .find_map(|x| SceneFile::find_model(&x.path, path));
+
+        // Names can repeat, so they are paired up in order
+        let mut matched = vec![false; old_models.len()];
+        for model in loaded.models {
+            let (mesh, material) = (model.mesh + mesh_offset, materials[model.material as usize]);
+            let existing = (0..old_models.len())
+                .find(|&i| !matched[i] && scene.models[old_models[i]].name == model.name);
+            let Some(i) = existing else {
+                let mut model = Model {
+                    mesh,
+                    material,
+                    ..model
+                };
+                if let Some(config) = &config {
+                    config.apply(&mut model);
+                }
+                scene.models.push(model);
+                continue;
+            };
+
+            matched[i] = true;
+            let current = &mut scene.models[old_models[i]];
+            replaced[current.mesh as usize] = mesh;
+            current.mesh = mesh;
+            // Keep materials assigned in the UI from outside the file
+            if old_materials.contains(&current.material) {
+                current.material = material;
+            }
+        }
+
+        // Models no longer in the file
+        for (&i, _) in old_models.iter().zip(matched).rev().filter(|x| !x.1) {
+            scene.models.remove(i);
+        }
+
+        // Materials of the file that no model uses any more, so the material
+        // table doesn't grow with every reload. Unused materials from outside
+        // the file, such as ones made in the UI, are kept.
+        let mut unused = (old_materials.iter().chain(&materials).copied())
+            .filter(|&i| !scene.models.iter().any(|x| x.material == i))
+            .collect::<Vec<_>>();
+        unused.sort_unstable();
+        unused.dedup();
+        for &i in unused.iter().rev() {
+            let material = scene.materials.remove(i as usize);
+            self.loaded.remove(&material.id);
+        }
+        for model in scene.models.iter_mut() {
+            model.material -= unused.iter().filter(|&&i| i < model.material).count() as u32;
+        }
+
+        scene.sources.retain(|x| x.obj.as_deref() != Some(path));
+        scene.sources.extend(loaded.sources);
+
+        let remap = scene.compact();
+        Remap {
+            meshes: replaced
+                .into_iter()
+                .map(|x| remap.meshes[x as usize])
+                .collect(),
+            ..remap
+        }
+    }
+}
+
+impl Default for AssetWatcher {
+    fn default() -> Self {
+        Self {
+            modified: HashMap::new(),
+            loaded: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+}
+
+fn without_textures(mut material: Material) -> Material {
+    material.textures_mut().into_iter().for_each(|x| *x = 0);
+    material
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::AssetWatcher;
+    use crate::{
+        scene::Scene,
+        types::{Material, NamedMaterial},
+    };
+
+    fn obj(path: &Path, material: &str) -> Scene {
+        let mut scene = Scene::placeholder();
+        scene.models[0].source = Some(path.to_owned());
+        scene.materials[0].name = material.into();
+        scene
+    }
+
+    #[test]
+    fn unused_materials_dropped() {
+        let path = Path::new("model.obj");
+        let mut scene = obj(path, "Old");
+        let custom = NamedMaterial::new("Custom", Material::defaults()[0]);
+        scene.materials.insert(0, custom);
+        scene.models[0].material = 1;
+
+        let mut assets = AssetWatcher::default();
+        assets.track(&scene);
+        for material in ["New", "Newer"] {
+            assets.reload(&mut scene, path, obj(path, material));
+        }
+
+        let names = (scene.materials.iter().map(|x| x.name.as_str())).collect::<Vec<_>>();
+        assert_eq!(names, ["Custom", "Newer"]);
+        assert_eq!(scene.models[0].material, 1);
+    }
+}
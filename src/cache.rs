@@ -0,0 +1,538 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytemuck::Pod;
+use compute::export::nalgebra::{Quaternion, SVector, UnitQuaternion, Vector2, Vector3, Vector4};
+use image::RgbaImage;
+use memmap2::Mmap;
+
+use crate::{
+    loader::Progress,
+    misc::next_id,
+    scene::{Scene, SourceFile},
+    types::{GpuMaterial, Light, LightKind, Material, Mesh, Model, NamedMaterial, Vertex},
+};
+
+const MAGIC: &[u8; 8] = b"RTSCENE\0";
+const VERSION: u32 = 4;
+
+/// Loads a scene from its cache next to `path` if none of the source files
+/// have changed, otherwise loads it from the sources. The cache is only
+/// rewritten if `write_cache` is set, so files added to a scene don't leave
+/// caches behind in the asset folders.
+pub fn load(path: &Path, write_cache: bool, progress: &Progress) -> Result<Scene> {
+    let cache = cache_path(path);
+    progress.start(format!("Reading {}", cache.display()), 0);
+    if let Ok(Some(scene)) = read(&cache) {
+        return Ok(scene);
+    }
+
+    let mut scene = Scene::empty();
+    scene.load(path, progress)?;
+    if write_cache {
+        if let Err(err) = write(&cache, &scene) {
+            println!("[!] Failed to write scene cache {cache:?}: {err}");
+        }
+    }
+    Ok(scene)
+}
+
+/// Loads a scene from its sources and writes its cache, returning the path
+/// of the cache file.
+pub fn bake(path: &Path) -> Result<PathBuf> {
+    let mut scene = Scene::empty();
+    scene.load(path, &Progress::default())?;
+
+    let cache = cache_path(path);
+    write(&cache, &scene)?;
+    Ok(cache)
+}
+
+fn cache_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".cache");
+    path.with_file_name(name)
+}
+
+/// Returns None if the cache is for an old version or any source changed.
+fn read(path: &Path) -> Result<Option<Scene>> {
+    let file = File::open(path)?;
+    // SAFETY: Cache files are only written by `write`, which replaces the
+    // file instead of modifying it in place.
+    let map = unsafe { Mmap::map(&file)? };
+    let mut reader = Reader::new(&map);
+
+    ensure!(reader.bytes(MAGIC.len())? == MAGIC, "Not a scene cache");
+    if reader.u32()? != VERSION {
+        return Ok(None);
+    }
+
+    let mut scene = Scene::empty();
+    for _ in 0..reader.u32()? {
+        let source = Source {
+            path: PathBuf::from(reader.string()?),
+            modified: reader.u64()?,
+            size: reader.u64()?,
+            hash: reader.u64()?,
+        };
+        let obj = reader.path()?;
+
+        if !source.is_current() {
+            return Ok(None);
+        }
+        scene
+            .sources
+            .push(SourceFile::new(&source.path, obj.as_deref()));
+    }
+
+    for _ in 0..reader.u32()? {
+        let [first_vertex, vertex_count, first_index, index_count] = reader.u32s()?;
+        scene.meshes.push(Mesh {
+            first_vertex,
+            vertex_count,
+            first_index,
+            index_count,
+        });
+    }
+
+    for _ in 0..reader.u32()? {
+        scene.models.push(Model {
+            name: reader.string()?,
+            id: next_id(),
+            source: reader.path()?,
+            material: reader.u32()?,
+            mesh: reader.u32()?,
+            position: reader.vector()?,
+            scale: reader.vector()?,
+            rotation: UnitQuaternion::new_unchecked(Quaternion::from(reader.vector::<4>()?)),
+            pivot: reader.vector()?,
+        });
+    }
+
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let mut gpu = GpuMaterial {
+            tag: reader.u32()?,
+            textures: Vector2::from(reader.u32s()?),
+            params: [Vector4::zeros(); 4],
+        };
+        for param in gpu.params.iter_mut() {
+            *param = reader.vector()?;
+        }
+        scene
+            .materials
+            .push(NamedMaterial::new(name, Material::from_gpu(&gpu)));
+    }
+
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let Some(&kind) = LightKind::ALL.get(reader.u32()? as usize) else {
+            bail!("Unknown light kind");
+        };
+
+        scene.lights.push(Light {
+            name,
+            enabled: reader.u32()? != 0,
+            color: reader.vector()?,
+            intensity: reader.f32()?,
+            position: reader.vector()?,
+            direction: reader.vector()?,
+            radius: reader.f32()?,
+            size: reader.vector()?,
+            cone: reader.vector()?,
+            angular_diameter: reader.f32()?,
+            ..Light::new(kind)
+        });
+    }
+
+    for _ in 0..reader.u32()? {
+        let [width, height] = reader.u32s()?;
+        let pixels = reader.slice::<u8>()?;
+        let image =
+            RgbaImage::from_raw(width, height, pixels.to_vec()).context("Invalid texture size")?;
+        scene.textures.push(Arc::new(image));
+    }
+
+    scene.verts = (reader.slice::<[f32; 8]>()?.iter())
+        .map(|x| Vertex {
+            position: Vector3::new(x[0], x[1], x[2]),
+            normal: Vector3::new(x[3], x[4], x[5]),
+            uv: Vector2::new(x[6], x[7]),
+        })
+        .collect();
+    scene.index = reader.slice::<u32>()?.to_vec();
+
+    Ok(Some(scene))
+}
+
+fn write(path: &Path, scene: &Scene) -> Result<()> {
+    let mut writer = Writer::default();
+    writer.bytes(MAGIC);
+    writer.u32(VERSION);
+
+    writer.u32(scene.sources.len() as u32);
+    for file in scene.sources.iter() {
+        let source = Source::new(&file.path)?;
+        writer.string(&source.path.to_string_lossy());
+        writer.u64(source.modified);
+        writer.u64(source.size);
+        writer.u64(source.hash);
+        writer.path(file.obj.as_deref());
+    }
+
+    writer.u32(scene.meshes.len() as u32);
+    for mesh in scene.meshes.iter() {
+        writer.u32s(&[
+            mesh.first_vertex,
+            mesh.vertex_count,
+            mesh.first_index,
+            mesh.index_count,
+        ]);
+    }
+
+    writer.u32(scene.models.len() as u32);
+    for model in scene.models.iter() {
+        writer.string(&model.name);
+        writer.path(model.source.as_deref());
+        writer.u32(model.material);
+        writer.u32(model.mesh);
+        writer.f32s(model.position.as_slice());
+        writer.f32s(model.scale.as_slice());
+        writer.f32s(model.rotation.coords.as_slice());
+        writer.f32s(model.pivot.as_slice());
+    }
+
+    writer.u32(scene.materials.len() as u32);
+    for material in scene.materials.iter() {
+        let gpu = material.material.to_gpu();
+        writer.string(&material.name);
+        writer.u32(gpu.tag);
+        writer.u32s(gpu.textures.as_slice());
+        for param in gpu.params {
+            writer.f32s(param.as_slice());
+        }
+    }
+
+    writer.u32(scene.lights.len() as u32);
+    for light in scene.lights.iter() {
+        writer.string(&light.name);
+        writer.u32(
+            LightKind::ALL
+                .iter()
+                .position(|&x| x == light.kind)
+                .unwrap() as u32,
+        );
+        writer.u32(light.enabled as u32);
+        writer.f32s(light.color.as_slice());
+        writer.f32(light.intensity);
+        writer.f32s(light.position.as_slice());
+        writer.f32s(light.direction.as_slice());
+        writer.f32(light.radius);
+        writer.f32s(light.size.as_slice());
+        writer.f32s(light.cone.as_slice());
+        writer.f32(light.angular_diameter);
+    }
+
+    writer.u32(scene.textures.len() as u32);
+    for texture in scene.textures.iter() {
+        writer.u32s(&[texture.width(), texture.height()]);
+        writer.slice::<u8>(texture.as_raw());
+    }
+
+    let verts = (scene.verts.iter())
+        .map(|x| {
+            let (p, n, uv) = (x.position, x.normal, x.uv);
+            [p.x, p.y, p.z, n.x, n.y, n.z, uv.x, uv.y]
+        })
+        .collect::<Vec<_>>();
+    writer.slice(&verts);
+    writer.slice(&scene.index);
+
+    // Written to the side and renamed so a partly written cache is never read
+    let temp = path.with_extension("cache.tmp");
+    fs::write(&temp, writer.data)?;
+    fs::rename(temp, path)?;
+    Ok(())
+}
+
+/// A file the scene was loaded from. The modification time is checked first
+/// and the hash only if it changed, so touching a file doesn't invalidate
+/// the cache.
+struct Source {
+    path: PathBuf,
+    modified: u64,
+    size: u64,
+    hash: u64,
+}
+
+impl Source {
+    fn new(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            modified: modified(&metadata),
+            size: metadata.len(),
+            hash: fnv1a(&fs::read(path)?),
+        })
+    }
+
+    fn is_current(&self) -> bool {
+        let Ok(metadata) = fs::metadata(&self.path) else {
+            return false;
+        };
+
+        if metadata.len() != self.size {
+            return false;
+        }
+
+        modified(&metadata) == self.modified
+            || fs::read(&self.path).is_ok_and(|x| fnv1a(&x) == self.hash)
+    }
+}
+
+fn modified(metadata: &fs::Metadata) -> u64 {
+    let modified = metadata.modified().ok();
+    let since_epoch = modified.and_then(|x| x.duration_since(UNIX_EPOCH).ok());
+    since_epoch.map_or(0, |x| x.as_nanos() as u64)
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Little endian values, except for bulk data which is in native order and
+/// aligned to [`ALIGNMENT`] so it can be borrowed straight from the map.
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+const ALIGNMENT: usize = 16;
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32s(&mut self, values: &[u32]) {
+        values.iter().for_each(|&x| self.u32(x));
+    }
+
+    fn f32s(&mut self, values: &[f32]) {
+        values.iter().for_each(|&x| self.f32(x));
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes(value.as_bytes());
+    }
+
+    /// An empty string stands for no path.
+    fn path(&mut self, value: Option<&Path>) {
+        self.string(&value.map(|x| x.to_string_lossy()).unwrap_or_default());
+    }
+
+    fn slice<T: Pod>(&mut self, values: &[T]) {
+        self.u64(values.len() as u64);
+        self.data
+            .resize(self.data.len().next_multiple_of(ALIGNMENT), 0);
+        self.bytes(bytemuck::cast_slice(values));
+    }
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .context("Unexpected end of scene cache")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u32s<const N: usize>(&mut self) -> Result<[u32; N]> {
+        let mut out = [0; N];
+        for x in out.iter_mut() {
+            *x = self.u32()?;
+        }
+        Ok(out)
+    }
+
+    fn vector<const N: usize>(&mut self) -> Result<SVector<f32, N>> {
+        let mut out = SVector::<f32, N>::zeros();
+        for x in out.iter_mut() {
+            *x = self.f32()?;
+        }
+        Ok(out)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+
+    fn path(&mut self) -> Result<Option<PathBuf>> {
+        let path = self.string()?;
+        Ok((!path.is_empty()).then(|| PathBuf::from(path)))
+    }
+
+    fn slice<T: Pod>(&mut self) -> Result<&'a [T]> {
+        let len = self.u64()? as usize;
+        self.offset = self.offset.next_multiple_of(ALIGNMENT);
+        let bytes = self.bytes(len * size_of::<T>())?;
+        bytemuck::try_cast_slice(bytes).map_err(|x| anyhow!("{x}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::PathBuf, sync::Arc};
+
+    use compute::export::nalgebra::{UnitQuaternion, Vector3};
+    use image::RgbaImage;
+
+    use super::{read, write};
+    use crate::{
+        scene::{Scene, SourceFile},
+        types::{Light, LightKind, Material},
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ray-tracing-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn scene(source: PathBuf) -> Scene {
+        let mut scene = Scene::placeholder();
+        scene.sources.push(SourceFile::new(&source, Some(&source)));
+
+        let model = &mut scene.models[0];
+        model.source = Some(source);
+        model.position = Vector3::new(1.0, 2.0, 3.0);
+        model.rotation = UnitQuaternion::from_euler_angles(0.1, 0.2, 0.3);
+
+        scene.materials[0].material = Material::defaults()[3];
+        scene.lights.push(Light::new(LightKind::Spot));
+        let texture = RgbaImage::from_fn(3, 2, |x, y| [x as u8, y as u8, 7, 255].into());
+        scene.textures.push(Arc::new(texture));
+        scene
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("cache-round-trip");
+        let (source, cache) = (dir.join("scene.obj"), dir.join("scene.obj.cache"));
+        fs::write(&source, "o Ground").unwrap();
+
+        let scene = scene(source);
+        write(&cache, &scene).unwrap();
+        let loaded = read(&cache).unwrap().expect("cache should be current");
+        fs::remove_dir_all(dir).unwrap();
+
+        let sources = |x: &Scene| {
+            (x.sources.iter().map(|x| (x.path.clone(), x.obj.clone()))).collect::<Vec<_>>()
+        };
+        assert_eq!(sources(&loaded), sources(&scene));
+
+        let meshes = |x: &Scene| {
+            (x.meshes.iter())
+                .map(|x| [x.first_vertex, x.vertex_count, x.first_index, x.index_count])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(meshes(&loaded), meshes(&scene));
+
+        assert_eq!(loaded.models.len(), scene.models.len());
+        for (a, b) in loaded.models.iter().zip(&scene.models) {
+            assert_eq!(
+                (&a.name, &a.source, a.material, a.mesh),
+                (&b.name, &b.source, b.material, b.mesh)
+            );
+            assert_eq!(
+                (a.position, a.scale, a.pivot),
+                (b.position, b.scale, b.pivot)
+            );
+            assert_eq!(a.rotation, b.rotation);
+        }
+
+        let materials = |x: &Scene| {
+            (x.materials.iter().map(|x| (x.name.clone(), x.material))).collect::<Vec<_>>()
+        };
+        assert_eq!(materials(&loaded), materials(&scene));
+
+        assert_eq!(loaded.lights.len(), scene.lights.len());
+        for (a, b) in loaded.lights.iter().zip(&scene.lights) {
+            assert!(a.name == b.name && a.kind == b.kind && a.enabled == b.enabled);
+            assert_eq!(
+                (a.color, a.position, a.direction),
+                (b.color, b.position, b.direction)
+            );
+            assert_eq!(
+                (a.intensity, a.radius, a.angular_diameter),
+                (b.intensity, b.radius, b.angular_diameter)
+            );
+            assert_eq!((a.size, a.cone), (b.size, b.cone));
+        }
+
+        assert_eq!(loaded.textures, scene.textures);
+
+        let verts =
+            |x: &Scene| (x.verts.iter().map(|x| (x.position, x.normal, x.uv))).collect::<Vec<_>>();
+        assert_eq!(verts(&loaded), verts(&scene));
+        assert_eq!(loaded.index, scene.index);
+    }
+
+    #[test]
+    fn invalidated_by_source_change() {
+        let dir = temp_dir("cache-invalidate");
+        let (source, cache) = (dir.join("scene.obj"), dir.join("scene.obj.cache"));
+        fs::write(&source, "o Ground").unwrap();
+
+        write(&cache, &scene(source.clone())).unwrap();
+        fs::write(&source, "o Changed Ground").unwrap();
+        let loaded = read(&cache).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(loaded.is_none());
+    }
+}
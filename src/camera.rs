@@ -10,23 +10,27 @@ use compute::{
     },
     interactive::GraphicsCtx,
 };
-use encase::ShaderType;
 use ordered_float::OrderedFloat;
 
-use crate::misc::{dragger, vec3_dragger};
+use crate::{
+    misc::{dragger, vec3_dragger},
+    wgsl::wgsl_struct,
+};
 
-#[derive(ShaderType, Clone, PartialEq)]
-pub struct Camera {
-    pub position: Vector3<f32>,
-    pub pitch: f32,
-    pub yaw: f32,
+wgsl_struct! {
+    #[derive(Clone, PartialEq)]
+    pub struct Camera {
+        pub position: Vector3<f32>,
+        pub pitch: f32,
+        pub yaw: f32,
 
-    pub fov: f32,
-    pub aspect: f32,
+        pub fov: f32,
+        pub aspect: f32,
+    }
 }
 
 impl Camera {
-    fn direction(&self) -> Vector3<f32> {
+    pub fn direction(&self) -> Vector3<f32> {
         Vector3::new(
             self.yaw.cos() * self.pitch.cos(),
             self.pitch.sin(),
@@ -34,6 +38,25 @@ impl Camera {
         )
         .normalize()
     }
+
+    /// Projects a point into normalized screen coordinates, the inverse of
+    /// `ray_direction` in `ray.wgsl`. Returns None for points behind the camera.
+    pub fn project(&self, point: Vector3<f32>) -> Option<Vector2<f32>> {
+        let forward = self.direction();
+        let right = Vector3::y().cross(&forward).normalize();
+        let up = forward.cross(&right).normalize();
+
+        let offset = point - self.position;
+        let depth = offset.dot(&forward);
+        if depth <= 1e-4 {
+            return None;
+        }
+
+        let scale = (self.fov * 0.5).tan();
+        let x = offset.dot(&right) / depth / (scale * self.aspect);
+        let y = offset.dot(&up) / depth / scale;
+        Some(Vector2::new(x + 0.5, 0.5 - y))
+    }
 }
 
 impl Camera {
@@ -1,28 +1,29 @@
-use std::borrow::Cow;
+/// Directory the shaders are read from when hot reloading.
+pub const SHADER_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders");
 
-use compute::export::wgpu::{ShaderModuleDescriptor, ShaderSource};
-
-macro_rules! include_shader {
-    ($name:expr) => {
-        include_str!(concat!("../shaders/", $name))
+macro_rules! embed_shaders {
+    ($($file:literal),* $(,)?) => {
+        &[$(($file, include_str!(concat!("../shaders/", $file)))),*]
     };
 }
 
-pub const COMPUTE_SOURCE: ShaderModuleDescriptor = ShaderModuleDescriptor {
-    label: None,
-    source: ShaderSource::Wgsl(Cow::Borrowed(concat!(
-        include_shader!("main.wgsl"),
-        include_shader!("types.wgsl"),
-        include_shader!("random.wgsl"),
-        include_shader!("misc.wgsl"),
-        include_shader!("ray.wgsl"),
-    ))),
-};
+/// The shader files embedded at compile time, as (name, source) pairs. The
+/// pipelines are built from these by following the `#include`s of their
+/// entry files, see [`crate::wgsl::preprocess`].
+pub const SHADER_FILES: &[(&str, &str)] = embed_shaders![
+    "main.wgsl",
+    "denoise.wgsl",
+    "render.wgsl",
+    "types.wgsl",
+    "random.wgsl",
+    "misc.wgsl",
+    "ray.wgsl",
+    "lights.wgsl",
+    "sky.wgsl",
+    "volume.wgsl",
+    "subsurface.wgsl",
+];
 
-pub const RENDER_SOURCE: ShaderModuleDescriptor = ShaderModuleDescriptor {
-    label: None,
-    source: ShaderSource::Wgsl(Cow::Borrowed(concat!(
-        include_shader!("render.wgsl"),
-        include_shader!("types.wgsl"),
-    ))),
-};
+/// Most à-trous iterations the denoiser runs. Each one has its own uniform
+/// buffer and pipeline, so the passes don't overwrite each other's settings.
+pub const MAX_DENOISE_ITERATIONS: u32 = 5;
@@ -0,0 +1,216 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use compute::export::egui::{ComboBox, DragValue, Ui};
+
+use crate::{misc::luminance, types::Pixel};
+
+const STATS_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct Convergence {
+    pub stop: StopCondition,
+    pub auto_capture: bool,
+    pub stopped: bool,
+
+    /// Incremented every time the accumulation is reset, so stats downloaded
+    /// from an older image can be thrown away.
+    generation: u32,
+    stats: Arc<Mutex<Option<Stats>>>,
+    last_stats: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StopCondition {
+    Never,
+    Samples(u32),
+    Time(f32),
+    Noise(f32),
+}
+
+#[derive(Clone, Copy)]
+pub struct Stats {
+    generation: u32,
+    /// Estimated root mean square error of the per-pixel luminance.
+    pub rmse: f32,
+    /// RMSE relative to the mean luminance of the image.
+    pub relative_error: f32,
+    /// Mean samples per pixel.
+    pub samples: f32,
+}
+
+impl Convergence {
+    pub fn reset(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.stopped = false;
+        *self.stats.lock().unwrap() = None;
+    }
+
+    pub fn stats(&self) -> Option<Stats> {
+        self.stats
+            .lock()
+            .unwrap()
+            .filter(|x| x.generation == self.generation)
+    }
+
+    /// If the stop condition was met or the noise dropped below `threshold`,
+    /// in percent of the mean luminance.
+    pub fn converged(&self, threshold: f32) -> bool {
+        self.stopped
+            || self
+                .stats()
+                .is_some_and(|x| x.relative_error * 100.0 <= threshold)
+    }
+
+    /// Returns true the first time the stop condition is met after a reset.
+    pub fn check(&mut self, samples: u32, elapsed: Duration) -> bool {
+        if self.stopped {
+            return false;
+        }
+
+        self.stopped = match self.stop {
+            StopCondition::Never => false,
+            StopCondition::Samples(target) => samples >= target,
+            StopCondition::Time(seconds) => elapsed.as_secs_f32() >= seconds,
+            StopCondition::Noise(target) => self
+                .stats()
+                .is_some_and(|x| x.relative_error * 100.0 <= target),
+        };
+
+        self.stopped
+    }
+
+    /// Returns a callback for downloading the accumulation buffer if enough
+    /// time has passed since the last time stats were computed.
+    pub fn request_stats(&mut self) -> Option<impl FnOnce(Vec<Pixel>) + Send + 'static> {
+        if self.last_stats.elapsed() < STATS_INTERVAL {
+            return None;
+        }
+
+        self.last_stats = Instant::now();
+        let (generation, stats) = (self.generation, self.stats.clone());
+        Some(move |pixels: Vec<Pixel>| {
+            *stats.lock().unwrap() = Some(Stats::compute(&pixels, generation));
+        })
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        let old_stop = self.stop;
+        ComboBox::from_label("Stop Condition")
+            .selected_text(self.stop.name())
+            .show_ui(ui, |ui| {
+                for condition in [
+                    StopCondition::Never,
+                    StopCondition::Samples(1024),
+                    StopCondition::Time(60.0),
+                    StopCondition::Noise(1.0),
+                ] {
+                    let selected = self.stop.name() == condition.name();
+                    if ui.selectable_label(selected, condition.name()).clicked() && !selected {
+                        self.stop = condition;
+                    }
+                }
+            });
+
+        ui.horizontal(|ui| match &mut self.stop {
+            StopCondition::Never => {}
+            StopCondition::Samples(samples) => {
+                ui.add(DragValue::new(samples).range(1..=u32::MAX));
+                ui.label("Target SPP");
+            }
+            StopCondition::Time(seconds) => {
+                ui.add(DragValue::new(seconds).range(0.0..=f32::MAX).suffix("s"));
+                ui.label("Time Budget");
+            }
+            StopCondition::Noise(percent) => {
+                ui.add(
+                    DragValue::new(percent)
+                        .range(0.0..=100.0)
+                        .speed(0.01)
+                        .suffix("%"),
+                );
+                ui.label("Noise Threshold");
+            }
+        });
+
+        ui.checkbox(&mut self.auto_capture, "Capture When Stopped");
+
+        if self.stop != old_stop {
+            self.stopped = false;
+        }
+    }
+}
+
+impl StopCondition {
+    fn name(&self) -> &'static str {
+        match self {
+            StopCondition::Never => "Never",
+            StopCondition::Samples(_) => "Samples",
+            StopCondition::Time(_) => "Time",
+            StopCondition::Noise(_) => "Noise",
+        }
+    }
+}
+
+impl Stats {
+    fn compute(pixels: &[Pixel], generation: u32) -> Self {
+        let (mut variance, mut mean, mut samples, mut sampled) = (0.0, 0.0, 0, 0);
+        for pixel in pixels.iter().filter(|x| x.samples > 0) {
+            let luminance = luminance(pixel.color);
+            // The variance of the pixel's mean is the sample variance over the sample count
+            variance += (pixel.moment - luminance * luminance).max(0.0) / pixel.samples as f32;
+            mean += luminance;
+            samples += pixel.samples as u64;
+            sampled += 1;
+        }
+
+        // Pixels that haven't been reached yet by tiles or adaptive sampling
+        // have no error estimate, so they're left out of the averages
+        let count = sampled.max(1) as f32;
+        let rmse = (variance / count).sqrt();
+        Self {
+            generation,
+            rmse,
+            relative_error: rmse / (mean / count).max(f32::EPSILON),
+            samples: samples as f32 / pixels.len().max(1) as f32,
+        }
+    }
+}
+
+impl Default for Convergence {
+    fn default() -> Self {
+        Self {
+            stop: StopCondition::Never,
+            auto_capture: false,
+            stopped: false,
+
+            generation: 0,
+            stats: Arc::new(Mutex::new(None)),
+            last_stats: Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use compute::export::nalgebra::Vector3;
+
+    use super::Stats;
+    use crate::types::Pixel;
+
+    #[test]
+    fn unsampled_pixels_ignored() {
+        let pixel = Pixel {
+            color: Vector3::repeat(1.0),
+            moment: 2.0,
+            samples: 4,
+        };
+        let full = Stats::compute(&[pixel; 4], 0);
+        let partial = Stats::compute(&[pixel, pixel, Pixel::default(), Pixel::default()], 0);
+
+        assert!((partial.rmse - full.rmse).abs() < 1e-6);
+        assert!((partial.relative_error - full.relative_error).abs() < 1e-6);
+        assert_eq!(partial.samples, full.samples / 2.0);
+    }
+}
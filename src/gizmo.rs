@@ -0,0 +1,276 @@
+use std::{array, f32::consts::TAU};
+
+use compute::export::{
+    egui::{
+        vec2, Area, Color32, Context, DragValue, Id, LayerId, Order, Pos2, Rect, Sense, Shape,
+        Stroke, Ui, Vec2,
+    },
+    nalgebra::{Unit, UnitQuaternion, Vector3},
+};
+
+use crate::{camera::Camera, types::Model};
+
+const AXIS_COLORS: [Color32; 3] = [
+    Color32::from_rgb(230, 70, 70),
+    Color32::from_rgb(100, 200, 80),
+    Color32::from_rgb(70, 120, 230),
+];
+/// How close the cursor has to be to a handle to grab it, in points.
+const GRAB_DISTANCE: f32 = 8.0;
+
+/// Translate, rotate and scale handles drawn over the selected model.
+pub struct Gizmo {
+    pub mode: GizmoMode,
+    /// Use the model's axes instead of the world axes. Scaling always
+    /// happens along the model's axes.
+    pub local: bool,
+    pub snap: bool,
+    /// Snapping increments for translation, rotation (in degrees) and scale.
+    pub snap_step: Vector3<f32>,
+
+    drag: Option<Drag>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum GizmoMode {
+    Translate,
+    Rotate,
+    Scale,
+}
+
+/// The handle being dragged and the model's transform when it was grabbed.
+struct Drag {
+    axis: usize,
+    start: Pos2,
+    position: Vector3<f32>,
+    rotation: UnitQuaternion<f32>,
+    scale: Vector3<f32>,
+}
+
+impl Gizmo {
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.mode, GizmoMode::Translate, "Translate");
+            ui.selectable_value(&mut self.mode, GizmoMode::Rotate, "Rotate");
+            ui.selectable_value(&mut self.mode, GizmoMode::Scale, "Scale");
+            ui.separator();
+            ui.selectable_value(&mut self.local, false, "World");
+            ui.selectable_value(&mut self.local, true, "Local");
+        });
+
+        ui.checkbox(&mut self.snap, "Snap");
+        ui.add_enabled_ui(self.snap, |ui| {
+            ui.horizontal(|ui| {
+                ui.add(
+                    DragValue::new(&mut self.snap_step.x)
+                        .range(0.001..=f32::MAX)
+                        .speed(0.01),
+                );
+                ui.add(
+                    DragValue::new(&mut self.snap_step.y)
+                        .range(0.1..=360.0)
+                        .suffix("°"),
+                );
+                ui.add(
+                    DragValue::new(&mut self.snap_step.z)
+                        .range(0.001..=f32::MAX)
+                        .speed(0.01),
+                );
+                ui.label("Steps");
+            });
+        });
+    }
+
+    /// Draws the handles for `model` and applies any drag to its transform.
+    /// Returns true if the model was changed.
+    pub fn show(&mut self, ctx: &Context, camera: &Camera, model: &mut Model) -> bool {
+        let screen = ctx.screen_rect();
+        let to_screen = |point: Vector3<f32>| {
+            let pos = camera.project(point)?;
+            Some(screen.min + vec2(pos.x, pos.y) * screen.size())
+        };
+
+        let origin = model.position;
+        let Some(center) = to_screen(origin) else {
+            self.drag = None;
+            return false;
+        };
+
+        // Keep the handles roughly the same size on screen
+        let depth = (origin - camera.position).dot(&camera.direction());
+        let length = depth * (camera.fov * 0.5).tan() * 0.3;
+
+        let local = self.local || self.mode == GizmoMode::Scale;
+        let axes: [Vector3<f32>; 3] = array::from_fn(|i| match local {
+            true => model.rotation * Vector3::ith(i, 1.0),
+            false => Vector3::ith(i, 1.0),
+        });
+        let handles = axes.map(|axis| self.handle(axis, origin, length, &to_screen));
+
+        let pointer = ctx.input(|x| x.pointer.hover_pos());
+        let hovered = pointer.and_then(|pointer| {
+            (0..3)
+                .map(|i| (i, distance_to_line(&handles[i], pointer)))
+                .filter(|(_, distance)| *distance < GRAB_DISTANCE)
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(i, _)| i)
+        });
+
+        // Capture the pointer while it's over a handle, so dragging doesn't
+        // also turn the camera and clicking doesn't pick another model.
+        let mut changed = false;
+        if hovered.is_some() || self.drag.is_some() {
+            let response = Area::new(Id::new("gizmo"))
+                .order(Order::Background)
+                .fixed_pos(screen.min)
+                .show(ctx, |ui| ui.allocate_rect(screen, Sense::drag()))
+                .inner;
+
+            if let (true, Some(axis), Some(start)) = (response.drag_started(), hovered, pointer) {
+                self.drag = Some(Drag {
+                    axis,
+                    start,
+                    position: model.position,
+                    rotation: model.rotation,
+                    scale: model.scale,
+                });
+            }
+
+            if let (Some(drag), Some(pointer)) = (&self.drag, pointer) {
+                let tip = handles[drag.axis].last().copied().unwrap_or(center);
+                let before = (model.position, model.rotation, model.scale);
+                self.apply(drag, model, camera, axes, (center, tip, pointer), length);
+                // Holding still (or within a snap step) isn't a change
+                changed = before != (model.position, model.rotation, model.scale);
+            }
+
+            if response.drag_stopped() {
+                self.drag = None;
+            }
+        }
+
+        let painter = ctx.layer_painter(LayerId::new(Order::Background, Id::new("gizmo_shapes")));
+        let active = self.drag.as_ref().map(|x| x.axis).or(hovered);
+        for (i, handle) in handles.iter().enumerate() {
+            let color = match active == Some(i) {
+                true => Color32::YELLOW,
+                false => AXIS_COLORS[i],
+            };
+            let stroke = Stroke::new(2.0, color);
+
+            match (self.mode, handle.last()) {
+                (GizmoMode::Translate, Some(&tip)) => painter.arrow(center, tip - center, stroke),
+                (GizmoMode::Scale, Some(&tip)) => {
+                    painter.line_segment([center, tip], stroke);
+                    painter.rect_filled(Rect::from_center_size(tip, Vec2::splat(8.0)), 0.0, color);
+                }
+                _ => {
+                    painter.add(Shape::line(handle.clone(), stroke));
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Screen space points making up the handle for an axis, a line for
+    /// translation and scaling or a ring around the axis for rotation.
+    fn handle(
+        &self,
+        axis: Vector3<f32>,
+        origin: Vector3<f32>,
+        length: f32,
+        to_screen: &impl Fn(Vector3<f32>) -> Option<Pos2>,
+    ) -> Vec<Pos2> {
+        match self.mode {
+            GizmoMode::Translate | GizmoMode::Scale => [origin, origin + axis * length]
+                .into_iter()
+                .map(to_screen)
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_default(),
+            GizmoMode::Rotate => {
+                let u = axis.cross(&Vector3::ith(axis.iamin(), 1.0)).normalize();
+                let v = axis.cross(&u);
+                (0..=64)
+                    .map(|i| i as f32 / 64.0 * TAU)
+                    .filter_map(|t| to_screen(origin + (u * t.cos() + v * t.sin()) * length))
+                    .collect()
+            }
+        }
+    }
+
+    /// Updates the model from the total pointer movement since the drag started,
+    /// given the screen positions of the gizmo's center, the dragged handle's
+    /// tip and the pointer.
+    fn apply(
+        &self,
+        drag: &Drag,
+        model: &mut Model,
+        camera: &Camera,
+        axes: [Vector3<f32>; 3],
+        (center, tip, pointer): (Pos2, Pos2, Pos2),
+        length: f32,
+    ) {
+        let snap = |value: f32, step: f32| match self.snap && step > 0.0 {
+            true => (value / step).round() * step,
+            false => value,
+        };
+
+        match self.mode {
+            GizmoMode::Translate | GizmoMode::Scale => {
+                // Project the pointer movement onto the axis on screen
+                let axis = tip - center;
+                let offset = (pointer - drag.start).dot(axis) / axis.length_sq().max(1.0) * length;
+
+                if self.mode == GizmoMode::Translate {
+                    model.position =
+                        drag.position + axes[drag.axis] * snap(offset, self.snap_step.x);
+                } else {
+                    let scale = drag.scale[drag.axis] * (1.0 + offset / length);
+                    model.scale[drag.axis] = snap(scale, self.snap_step.z).max(0.001);
+                }
+            }
+            GizmoMode::Rotate => {
+                let angle = |pos: Pos2| (center.y - pos.y).atan2(pos.x - center.x);
+                let mut theta = angle(pointer) - angle(drag.start);
+                // Counterclockwise on screen is only a positive rotation if
+                // the axis points towards the camera
+                if axes[drag.axis].dot(&(camera.position - drag.position)) < 0.0 {
+                    theta = -theta;
+                }
+                let theta = snap(theta.to_degrees(), self.snap_step.y).to_radians();
+
+                model.rotation = if self.local {
+                    let axis = Vector3::ith_axis(drag.axis);
+                    drag.rotation * UnitQuaternion::from_axis_angle(&axis, theta)
+                } else {
+                    let axis = Unit::new_normalize(axes[drag.axis]);
+                    UnitQuaternion::from_axis_angle(&axis, theta) * drag.rotation
+                };
+            }
+        }
+    }
+}
+
+fn distance_to_line(points: &[Pos2], pos: Pos2) -> f32 {
+    points
+        .windows(2)
+        .map(|x| {
+            let (a, b) = (x[0], x[1]);
+            let t = ((pos - a).dot(b - a) / (b - a).length_sq().max(f32::EPSILON)).clamp(0.0, 1.0);
+            pos.distance(a + (b - a) * t)
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+impl Default for Gizmo {
+    fn default() -> Self {
+        Self {
+            mode: GizmoMode::Translate,
+            local: false,
+            snap: false,
+            snap_step: Vector3::new(0.1, 15.0, 0.1),
+            drag: None,
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use compute::export::egui::{Context, RichText, Ui};
+use ordered_float::OrderedFloat;
+
+use crate::{
+    camera::Camera,
+    misc::hash,
+    scene::Remap,
+    sky::SkySettings,
+    types::{Model, NamedMaterial, Uniform},
+};
+
+/// Number of edits that can be undone.
+const MAX_ENTRIES: usize = 100;
+
+/// Everything that can be undone.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub models: Vec<Model>,
+    pub materials: Vec<NamedMaterial>,
+    /// Holds the camera and render settings.
+    pub uniform: Uniform,
+    pub sky: SkySettings,
+}
+
+/// Undo and redo for edits to the models, materials, camera and render
+/// settings. Changes are only recorded once no buttons are held and no text
+/// is being edited, so dragging a value makes a single entry.
+#[derive(Default)]
+pub struct History {
+    /// Past edits with the state from before them, newest last.
+    undo: Vec<Entry>,
+    /// Undone edits with the state from after them, next to redo last.
+    redo: Vec<Entry>,
+    /// The state after the last recorded edit, None until the first record.
+    current: Option<(Snapshot, u64)>,
+    pub show: bool,
+}
+
+struct Entry {
+    label: String,
+    snapshot: Snapshot,
+    hash: u64,
+}
+
+impl History {
+    /// Records the state if it changed since the last call and the user
+    /// isn't in the middle of changing it.
+    pub fn record(&mut self, ctx: &Context, hash: u64, snapshot: impl FnOnce() -> Snapshot) {
+        if self.current.as_ref().is_some_and(|x| x.1 == hash) {
+            return;
+        }
+
+        let busy = ctx.input(|x| x.pointer.any_down() || !x.keys_down.is_empty());
+        if busy || ctx.wants_keyboard_input() {
+            return;
+        }
+
+        let snapshot = snapshot();
+        if let Some((before, before_hash)) = self.current.take() {
+            self.undo.push(Entry {
+                label: describe(&before, &snapshot),
+                snapshot: before,
+                hash: before_hash,
+            });
+            if self.undo.len() > MAX_ENTRIES {
+                self.undo.remove(0);
+            }
+            self.redo.clear();
+        }
+        self.current = Some((snapshot, hash));
+    }
+
+    /// Steps back through the history, returning the state to restore.
+    pub fn undo(&mut self, steps: usize) -> Option<Snapshot> {
+        let mut restore = None;
+        for _ in 0..steps {
+            let Some(entry) = self.undo.pop() else { break };
+            let (snapshot, hash) = self.current.replace((entry.snapshot.clone(), entry.hash))?;
+            self.redo.push(Entry {
+                label: entry.label,
+                snapshot,
+                hash,
+            });
+            restore = Some(entry.snapshot);
+        }
+        restore
+    }
+
+    /// Steps forward through undone edits, returning the state to restore.
+    pub fn redo(&mut self, steps: usize) -> Option<Snapshot> {
+        let mut restore = None;
+        for _ in 0..steps {
+            let Some(entry) = self.redo.pop() else { break };
+            let (snapshot, hash) = self.current.replace((entry.snapshot.clone(), entry.hash))?;
+            self.undo.push(Entry {
+                label: entry.label,
+                snapshot,
+                hash,
+            });
+            restore = Some(entry.snapshot);
+        }
+        restore
+    }
+
+    /// Points every snapshot at the meshes and textures of a scene that was
+    /// compacted, see [`Snapshot::remap`].
+    pub fn remap(&mut self, remap: &Remap, models: &[Model], materials: &[NamedMaterial]) {
+        let entries = (self.undo.iter_mut().chain(self.redo.iter_mut())).map(|x| &mut x.snapshot);
+        for snapshot in entries.chain(self.current.as_mut().map(|x| &mut x.0)) {
+            snapshot.remap(remap, models, materials);
+        }
+    }
+
+    /// Forgets all edits, used when a different scene is loaded.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.current = None;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Lists the edits, returning how many steps to undo (negative) or redo
+    /// (positive) if one was clicked.
+    pub fn ui(&self, ui: &mut Ui) -> Option<isize> {
+        let mut jump = None;
+        for (i, entry) in self.undo.iter().enumerate() {
+            if ui.selectable_label(false, &entry.label).clicked() {
+                jump = Some(i as isize - self.undo.len() as isize);
+            }
+        }
+
+        ui.label(RichText::new("Current").strong());
+
+        for (i, entry) in self.redo.iter().rev().enumerate() {
+            let label = ui.selectable_label(false, &entry.label);
+            if label.on_hover_text("Undone").clicked() {
+                jump = Some(i as isize + 1);
+            }
+        }
+
+        jump
+    }
+}
+
+impl Snapshot {
+    /// Models and materials still in the scene take their current mesh and
+    /// textures, the rest follow the remap. Models whose mesh was dropped
+    /// can't be brought back, so they are removed.
+    fn remap(&mut self, remap: &Remap, models: &[Model], materials: &[NamedMaterial]) {
+        self.models.retain_mut(|model| {
+            let current = models.iter().find(|x| x.id == model.id).map(|x| x.mesh);
+            let Some(mesh) = current.or(remap.meshes[model.mesh as usize]) else {
+                return false;
+            };
+            model.mesh = mesh;
+            true
+        });
+
+        for material in self.materials.iter_mut() {
+            for texture in material.material.textures_mut() {
+                *texture = remap.textures[*texture as usize];
+            }
+
+            // Materials reloaded from a file moved to its new textures
+            if let Some(current) = materials.iter().find(|x| x.id == material.id) {
+                let mut current = current.material;
+                let textures = current.textures_mut();
+                for (texture, new) in material.material.textures_mut().into_iter().zip(textures) {
+                    *texture = *new;
+                }
+            }
+        }
+    }
+}
+
+/// Hash of the state in a [`Snapshot`], used to detect changes without
+/// copying it every frame.
+pub fn state_hash(models: &[Model], materials: &[NamedMaterial], uniform: &Uniform) -> u64 {
+    // The aspect ratio follows the window, so resizing isn't an edit
+    let uniform = Uniform {
+        camera: Camera {
+            aspect: 0.0,
+            ..uniform.camera.clone()
+        },
+        ..uniform.clone()
+    };
+    // Settings that don't affect accumulation aren't part of the uniform's hash
+    let display = (
+        OrderedFloat(uniform.exposure),
+        OrderedFloat(uniform.adaptive_threshold),
+        uniform.adaptive_min_samples,
+    );
+    hash((models, materials, &uniform, display))
+}
+
+fn describe(before: &Snapshot, after: &Snapshot) -> String {
+    let (old, new) = (&before.models, &after.models);
+    if old.len() != new.len() {
+        let action = if old.len() < new.len() {
+            "Add"
+        } else {
+            "Delete"
+        };
+        return format!("{action} Model");
+    }
+    if let Some((_, model)) = old.iter().zip(new).find(|(a, b)| hash(a) != hash(b)) {
+        return format!("Edit {}", model.name);
+    }
+
+    let (old, new) = (&before.materials, &after.materials);
+    if old.len() != new.len() {
+        return "Import Materials".into();
+    }
+    if let Some((_, material)) = old.iter().zip(new).find(|(a, b)| hash(a) != hash(b)) {
+        return format!("Edit {}", material.name);
+    }
+
+    if hash(&before.uniform.camera) != hash(&after.uniform.camera) {
+        return "Move Camera".into();
+    }
+    "Change Settings".into()
+}
@@ -0,0 +1,103 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{cache, scene::Scene};
+
+/// Loads a scene file, OBJ or glTF on a background thread, so the window can
+/// stay responsive while large scenes are parsed and their textures decoded.
+pub struct SceneLoader {
+    pub path: PathBuf,
+    pub mode: LoadMode,
+    pub progress: Arc<Progress>,
+    handle: JoinHandle<Result<Scene>>,
+}
+
+/// What to do with the scene once it's loaded.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoadMode {
+    /// Replace the current scene.
+    Replace,
+    /// Add it to the current scene.
+    Append,
+    /// Replace the models previously loaded from the same OBJ file, see
+    /// [`crate::assets::AssetWatcher::reload`].
+    Reload,
+}
+
+/// Progress of the current loading stage, updated from the loading threads.
+#[derive(Default)]
+pub struct Progress {
+    status: Mutex<String>,
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl SceneLoader {
+    pub fn spawn(path: impl AsRef<Path>, mode: LoadMode) -> Self {
+        let path = path.as_ref().to_owned();
+        let progress = Arc::new(Progress::default());
+
+        let handle = thread::spawn({
+            let (path, progress) = (path.clone(), progress.clone());
+            move || match mode {
+                // Only scenes opened on their own get a cache written
+                LoadMode::Replace | LoadMode::Append => {
+                    cache::load(&path, mode == LoadMode::Replace, &progress)
+                }
+                // Reloads only happen once a source changed, so there's no
+                // current cache to read
+                LoadMode::Reload => {
+                    let mut scene = Scene::empty();
+                    scene.load(&path, &progress)?;
+                    Ok(scene)
+                }
+            }
+        });
+
+        Self {
+            path,
+            mode,
+            progress,
+            handle,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    pub fn join(self) -> Result<Scene> {
+        (self.handle.join()).unwrap_or_else(|_| Err(anyhow!("Loading {:?} panicked", self.path)))
+    }
+}
+
+impl Progress {
+    /// Starts a new stage made up of `total` steps. A total of zero means the
+    /// amount of work isn't known.
+    pub fn start(&self, status: impl Into<String>, total: usize) {
+        *self.status.lock().unwrap() = status.into();
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn step(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> String {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn fraction(&self) -> Option<f32> {
+        let total = self.total.load(Ordering::Relaxed);
+        (total > 0).then(|| self.done.load(Ordering::Relaxed) as f32 / total as f32)
+    }
+}
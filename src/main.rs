@@ -1,29 +1,69 @@
-use std::time::Instant;
+use std::{
+    env,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use anyhow::{Ok, Result};
 use camera::Camera;
 use compute::{
     export::{
         nalgebra::{Vector2, Vector3},
-        wgpu::{Features, PowerPreference, ShaderStages},
+        wgpu::{Features, PowerPreference},
         winit::window::WindowAttributes,
     },
     gpu::Gpu,
 };
+use convergence::Convergence;
+use gizmo::Gizmo;
+use history::History;
+use loader::{LoadMode, SceneLoader};
 
 mod app;
+mod assets;
+mod cache;
 mod camera;
 mod consts;
+mod convergence;
+mod gizmo;
+mod history;
+mod loader;
+mod materials;
 mod misc;
 mod scene;
+mod scene_file;
+mod shaders;
+mod sky;
+mod tiles;
 mod types;
 mod ui;
-use app::App;
-use consts::{COMPUTE_SOURCE, RENDER_SOURCE};
+mod wgsl;
+use app::{compute_pipeline, denoise_pipeline, render_pipeline, App};
+use assets::AssetWatcher;
+use consts::MAX_DENOISE_ITERATIONS;
 use scene::Scene;
-use types::{Flags, Uniform};
+use shaders::Shaders;
+use sky::SkySettings;
+use tiles::Tiles;
+use types::{Aov, Denoise, EulerOrder, Flags, Fog, Pixel, ShaderFeatures, Uniform, VolumeMaterial};
 
 fn main() -> Result<()> {
+    // `ray-tracing [scene]` opens a scene, `ray-tracing bake <scenes...>`
+    // writes their caches without opening a window
+    let mut args = env::args().skip(1);
+    let scene_path = match args.next() {
+        Some(command) if command == "bake" => {
+            for path in args {
+                let cache = cache::bake(Path::new(&path))?;
+                println!("[*] Baked {path} into {cache:?}");
+            }
+            return Ok(());
+        }
+        Some(path) => path,
+        None => "scenes/lens.obj".into(),
+    };
+
     let gpu = Gpu::builder()
         .power_preference(PowerPreference::HighPerformance)
         .with_features(
@@ -33,60 +73,137 @@ fn main() -> Result<()> {
         .with_raytracing()
         .build()?;
 
-    let mut scene = Scene::empty();
-    scene.load("scenes/lens.obj")?;
-
+    // The window opens with a placeholder that's swapped out once loaded
+    let scene = Scene::placeholder();
     let buffers = scene.finish(&gpu)?;
     let uniform_buffer = gpu.create_uniform(&Uniform::default())?;
-    let accumulation_buffer = gpu.create_storage::<Vec<Vector3<f32>>>(&vec![])?;
-
-    let sampler = gpu.create_sampler();
-    let compute_pipeline = gpu
-        .compute_pipeline(COMPUTE_SOURCE)
-        .bind(&uniform_buffer)
-        .bind(&accumulation_buffer)
-        .bind(&buffers.models)
-        .bind(&buffers.acceleration)
-        .bind(&buffers.vertex)
-        .bind(&buffers.index)
-        .bind(&sampler)
-        .bind(&buffers.textures)
-        .finish();
-    let render_pipeline = gpu
-        .render_pipeline(RENDER_SOURCE)
-        .bind(&uniform_buffer, ShaderStages::FRAGMENT)
-        .bind(&accumulation_buffer, ShaderStages::FRAGMENT)
-        .finish();
+    let accumulation_buffer = gpu.create_storage::<Vec<Pixel>>(&vec![])?;
+    let aov_buffer = gpu.create_storage::<Vec<Aov>>(&vec![])?;
+    let tile_buffer = gpu.create_storage::<Vec<Vector2<u32>>>(&vec![])?;
+
+    let denoise_buffer = gpu.create_uniform(&Denoise::default())?;
+    let denoise_passes = (0..MAX_DENOISE_ITERATIONS)
+        .map(|_| gpu.create_uniform(&Denoise::default()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let denoise_a = gpu.create_storage::<Vec<Vector3<f32>>>(&vec![])?;
+    let denoise_b = gpu.create_storage::<Vec<Vector3<f32>>>(&vec![])?;
+
+    let shaders = Shaders::new(ShaderFeatures::all());
+    let compute_pipeline = compute_pipeline(
+        &gpu,
+        &shaders,
+        &uniform_buffer,
+        &accumulation_buffer,
+        &aov_buffer,
+        &tile_buffer,
+        &buffers,
+    );
+    let denoise_pipelines = denoise_passes
+        .iter()
+        .map(|pass| {
+            denoise_pipeline(
+                &gpu,
+                &shaders,
+                &uniform_buffer,
+                pass,
+                &accumulation_buffer,
+                &aov_buffer,
+                [&denoise_a, &denoise_b],
+            )
+        })
+        .collect();
+    let render_pipeline = render_pipeline(
+        &gpu,
+        &shaders,
+        &uniform_buffer,
+        &denoise_buffer,
+        &accumulation_buffer,
+        &aov_buffer,
+        [&denoise_a, &denoise_b],
+    );
 
     gpu.create_window(
         WindowAttributes::default().with_title("Ray Tracing"),
         App {
             compute_pipeline,
+            denoise_pipelines,
             render_pipeline,
+            shaders,
 
             uniform_buffer,
             accumulation_buffer,
+            aov_buffer,
+            denoise_buffer,
+            denoise_passes,
+            denoise_buffers: [denoise_a, denoise_b],
 
-            model_buffer: buffers.models,
-            acceleration_structure: buffers.acceleration,
-            transform_buffer: buffers.transformation,
             uniform: Uniform {
                 window: Vector2::zeros(),
                 camera: Camera::default(),
                 frame: 0,
-                accumulation_frame: 1,
+                accumulation_frame: 0,
                 flags: Flags::empty().bits(),
 
                 exposure: 1.0,
                 environment: 1.0,
                 max_bounces: 10,
                 samples: 5,
+
+                adaptive_threshold: 0.01,
+                adaptive_min_samples: 16,
+
+                roulette_depth: 3,
+                firefly_clamp: 0.0,
+                selected: 0,
+
+                sky: SkySettings::default().to_gpu(),
+                fog: Fog {
+                    medium: VolumeMaterial {
+                        density: 0.0,
+                        albedo: Vector3::repeat(0.9),
+                        anisotropy: 0.0,
+                    },
+                    height: 10.0,
+                },
+            },
+
+            denoise: Denoise {
+                iteration: 0,
+                iterations: 3,
+                strength: 0.5,
             },
+            denoise_enabled: false,
+            denoise_cutoff: 1.0,
+            convergence: Convergence::default(),
+            tiles: Tiles::default(),
+            tile_buffer,
+            sky: SkySettings::default(),
 
             models: scene.models,
+            materials: scene.materials,
+            library_path: "materials.toml".into(),
+            library_error: None,
+            lights: scene.lights,
+            meshes: scene.meshes,
+            verts: scene.verts,
+            index: scene.index,
+            textures: scene.textures,
+            buffers,
+            gpu: gpu.clone(),
+            loading: vec![SceneLoader::spawn(scene_path, LoadMode::Replace)],
+            sources: Vec::new(),
+            assets: AssetWatcher::default(),
+            import_path: String::new(),
+            import_error: None,
             last_frame: Instant::now(),
             last_invaladation: Instant::now(),
             last_window: Vector2::zeros(),
+            cursor: None,
+            selected: None,
+            gizmo: Gizmo::default(),
+            history: History::default(),
+            euler_order: EulerOrder::default(),
+            picked: Arc::new(Mutex::new(None)),
             accumulate: true,
             screen_fraction: 2,
         },
@@ -0,0 +1,222 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use compute::export::nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{
+    DielectricMaterial, Material, MetalMaterial, NamedMaterial, SubsurfaceMaterial, VolumeMaterial,
+};
+
+/// A TOML file of named materials that can be shared between scenes.
+/// Textures are tied to the OBJ they were loaded with, so they aren't saved.
+#[derive(Serialize, Deserialize)]
+struct MaterialLibrary {
+    #[serde(default)]
+    materials: Vec<MaterialConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MaterialConfig {
+    name: String,
+    #[serde(flatten)]
+    material: MaterialKindConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MaterialKindConfig {
+    Metal {
+        diffuse_color: [f32; 3],
+        specular_color: [f32; 3],
+        specular_probability: f32,
+        roughness: f32,
+        emission_color: [f32; 3],
+        emission_strength: f32,
+        anisotropy: f32,
+        anisotropy_rotation: f32,
+        film_thickness: f32,
+        film_refractive_index: f32,
+    },
+    Dielectric {
+        refractive_index: f32,
+        roughness: f32,
+        anisotropy: f32,
+        anisotropy_rotation: f32,
+        film_thickness: f32,
+        film_refractive_index: f32,
+    },
+    Volume {
+        density: f32,
+        albedo: [f32; 3],
+        anisotropy: f32,
+    },
+    Subsurface {
+        albedo: [f32; 3],
+        mean_free_path: [f32; 3],
+        refractive_index: f32,
+    },
+}
+
+pub fn load_library(path: impl AsRef<Path>) -> Result<Vec<NamedMaterial>> {
+    let library = toml::from_str::<MaterialLibrary>(&fs::read_to_string(path)?)?;
+    Ok(library
+        .materials
+        .into_iter()
+        .map(|x| NamedMaterial::new(x.name, x.material.into_material()))
+        .collect())
+}
+
+pub fn save_library(path: impl AsRef<Path>, materials: &[NamedMaterial]) -> Result<()> {
+    let library = MaterialLibrary {
+        materials: materials
+            .iter()
+            .map(|x| MaterialConfig {
+                name: x.name.clone(),
+                material: MaterialKindConfig::from_material(&x.material),
+            })
+            .collect(),
+    };
+
+    fs::write(path, toml::to_string(&library)?)?;
+    Ok(())
+}
+
+pub fn presets() -> [(&'static str, Material); 6] {
+    let metal = |color: [f32; 3], roughness: f32| {
+        Material::Metal(MetalMaterial {
+            diffuse_color: Vector3::from(color),
+            specular_color: Vector3::from(color),
+            specular_probability: 1.0,
+            roughness,
+            film_refractive_index: 1.33,
+            ..MetalMaterial::default()
+        })
+    };
+    let dielectric = |refractive_index: f32| {
+        Material::Dielectric(DielectricMaterial {
+            refractive_index,
+            film_refractive_index: 1.33,
+            ..DielectricMaterial::default()
+        })
+    };
+
+    [
+        ("Gold", metal([1.0, 0.766, 0.336], 0.2)),
+        ("Copper", metal([0.955, 0.638, 0.538], 0.25)),
+        ("Glass", dielectric(1.5)),
+        ("Water", dielectric(1.333)),
+        ("Diamond", dielectric(2.418)),
+        (
+            "Rubber",
+            Material::Metal(MetalMaterial {
+                diffuse_color: Vector3::repeat(0.05),
+                specular_color: Vector3::repeat(0.5),
+                specular_probability: 0.04,
+                roughness: 0.8,
+                film_refractive_index: 1.33,
+                ..MetalMaterial::default()
+            }),
+        ),
+    ]
+}
+
+impl MaterialKindConfig {
+    fn from_material(material: &Material) -> Self {
+        match *material {
+            Material::Metal(x) => MaterialKindConfig::Metal {
+                diffuse_color: x.diffuse_color.into(),
+                specular_color: x.specular_color.into(),
+                specular_probability: x.specular_probability,
+                roughness: x.roughness,
+                emission_color: x.emission_color.into(),
+                emission_strength: x.emission_strength,
+                anisotropy: x.anisotropy,
+                anisotropy_rotation: x.anisotropy_rotation,
+                film_thickness: x.film_thickness,
+                film_refractive_index: x.film_refractive_index,
+            },
+            Material::Dielectric(x) => MaterialKindConfig::Dielectric {
+                refractive_index: x.refractive_index,
+                roughness: x.roughness,
+                anisotropy: x.anisotropy,
+                anisotropy_rotation: x.anisotropy_rotation,
+                film_thickness: x.film_thickness,
+                film_refractive_index: x.film_refractive_index,
+            },
+            Material::Volume(x) => MaterialKindConfig::Volume {
+                density: x.density,
+                albedo: x.albedo.into(),
+                anisotropy: x.anisotropy,
+            },
+            Material::Subsurface(x) => MaterialKindConfig::Subsurface {
+                albedo: x.albedo.into(),
+                mean_free_path: x.mean_free_path.into(),
+                refractive_index: x.refractive_index,
+            },
+        }
+    }
+
+    fn into_material(self) -> Material {
+        match self {
+            MaterialKindConfig::Metal {
+                diffuse_color,
+                specular_color,
+                specular_probability,
+                roughness,
+                emission_color,
+                emission_strength,
+                anisotropy,
+                anisotropy_rotation,
+                film_thickness,
+                film_refractive_index,
+            } => Material::Metal(MetalMaterial {
+                diffuse_color: Vector3::from(diffuse_color),
+                specular_color: Vector3::from(specular_color),
+                specular_probability,
+                roughness,
+                emission_color: Vector3::from(emission_color),
+                emission_strength,
+                diffuse_texture: 0,
+                normal_texture: 0,
+                anisotropy,
+                anisotropy_rotation,
+                film_thickness,
+                film_refractive_index,
+            }),
+            MaterialKindConfig::Dielectric {
+                refractive_index,
+                roughness,
+                anisotropy,
+                anisotropy_rotation,
+                film_thickness,
+                film_refractive_index,
+            } => Material::Dielectric(DielectricMaterial {
+                refractive_index,
+                roughness,
+                anisotropy,
+                anisotropy_rotation,
+                film_thickness,
+                film_refractive_index,
+            }),
+            MaterialKindConfig::Volume {
+                density,
+                albedo,
+                anisotropy,
+            } => Material::Volume(VolumeMaterial {
+                density,
+                albedo: Vector3::from(albedo),
+                anisotropy,
+            }),
+            MaterialKindConfig::Subsurface {
+                albedo,
+                mean_free_path,
+                refractive_index,
+            } => Material::Subsurface(SubsurfaceMaterial {
+                albedo: Vector3::from(albedo),
+                mean_free_path: Vector3::from(mean_free_path),
+                refractive_index,
+            }),
+        }
+    }
+}
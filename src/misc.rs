@@ -40,6 +40,22 @@ pub fn vec3_dragger<Num: Numeric>(
     });
 }
 
+// From https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+pub fn tone_map(x: Vector3<f32>) -> Vector3<f32> {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    x.map(|x| ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(0.0, 1.0))
+}
+
+pub fn luminance(color: Vector3<f32>) -> f32 {
+    color.dot(&Vector3::new(0.2126, 0.7152, 0.0722))
+}
+
+pub fn color_edit(ui: &mut Ui, color: &mut Vector3<f32>) {
+    let mut rgb = [color.x, color.y, color.z];
+    ui.color_edit_button_rgb(&mut rgb);
+    *color = Vector3::from(rgb);
+}
+
 pub fn hash<T: Hash>(item: T) -> u64 {
     let mut hasher = DefaultHasher::new();
     item.hash(&mut hasher);
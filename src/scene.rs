@@ -1,36 +1,95 @@
-use std::{fs::File, io::BufReader, mem, path::Path};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    fs::File,
+    io::BufReader,
+    mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::SystemTime,
+};
 
-use anyhow::{Ok, Result};
+use anyhow::{Context, Ok, Result};
 use compute::{
     bindings::{
         acceleration_structure::{AccelerationStructure, Geometry, GeometryPrimitive},
         BlasBuffer, TextureCollection,
     },
-    export::nalgebra::{Matrix4, Matrix4x3, Vector2, Vector3},
+    export::nalgebra::{Matrix4, Matrix4x3, Quaternion, UnitQuaternion, Vector2, Vector3},
     gpu::Gpu,
 };
-use image::{imageops, ImageFormat, RgbaImage};
+use gltf::{buffer, image::Format, mesh::Mode, Gltf};
+use image::{
+    imageops, DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, ImageFormat, Luma, LumaA, Rgb,
+    Rgb32FImage, RgbImage, Rgba, Rgba32FImage, RgbaImage,
+};
 use tobj::LoadOptions;
 
 use crate::{
+    loader::Progress,
     misc::{next_id, GetUnknownMaterialParam},
-    types::{Material, MetalMaterial, Model, ModelBuffer, Vertex},
+    scene_file::SceneFile,
+    types::{
+        GpuLight, GpuMaterial, GpuModel, Light, LightBuffer, Material, MaterialBuffer, Mesh,
+        MetalMaterial, Model, ModelBuffer, NamedMaterial, TransformBuffer, Vertex,
+    },
 };
 
 pub struct Scene {
-    pub primitives: Vec<GeometryPrimitive>,
+    pub meshes: Vec<Mesh>,
     pub models: Vec<Model>,
-    pub textures: Vec<RgbaImage>,
+    pub materials: Vec<NamedMaterial>,
+    pub lights: Vec<Light>,
+    /// Already flipped to match the texture coordinates. Shared so copies
+    /// of the scene stay cheap.
+    pub textures: Vec<Arc<RgbaImage>>,
 
     pub verts: Vec<Vertex>,
     pub index: Vec<u32>,
+    /// Every file read while loading, used to invalidate the scene cache and
+    /// to reload OBJ files when they change.
+    pub sources: Vec<SourceFile>,
+}
+
+/// Where meshes and textures ended up after [`Scene::compact`], used to fix
+/// up copies of the models and materials such as the undo history.
+pub struct Remap {
+    /// New index of each old mesh, None if it was dropped.
+    pub meshes: Vec<Option<u32>>,
+    /// New id of each old texture id, zero if it was dropped. Like texture
+    /// ids themselves, zero stays untextured.
+    pub textures: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// The OBJ or glTF file this was loaded for, None for scene files.
+    pub obj: Option<PathBuf>,
+    /// Modification time when the file was loaded, so changes made before
+    /// the asset watcher first looks at it aren't missed.
+    pub modified: Option<SystemTime>,
+}
+
+impl SourceFile {
+    pub fn new(path: &Path, obj: Option<&Path>) -> Self {
+        Self {
+            path: path.to_owned(),
+            obj: obj.map(Path::to_owned),
+            modified: fs::metadata(path).and_then(|x| x.modified()).ok(),
+        }
+    }
 }
 
 pub struct SceneBuffers {
     pub models: ModelBuffer,
+    pub materials: MaterialBuffer,
+    pub lights: LightBuffer,
     pub vertex: BlasBuffer<Vertex>,
     pub index: BlasBuffer<u32>,
-    pub transformation: BlasBuffer<Matrix4x3<f32>>,
+    pub transformation: TransformBuffer,
     pub acceleration: AccelerationStructure<Vertex>,
     pub textures: TextureCollection,
 }
@@ -38,33 +97,102 @@ pub struct SceneBuffers {
 impl Scene {
     pub fn empty() -> Self {
         Self {
-            primitives: Vec::new(),
+            meshes: Vec::new(),
             models: Vec::new(),
+            materials: Vec::new(),
+            lights: Vec::new(),
             textures: Vec::new(),
 
             verts: Vec::new(),
             index: Vec::new(),
+            sources: Vec::new(),
         }
     }
 
-    pub fn finish(&mut self, gpu: &Gpu) -> Result<SceneBuffers> {
+    /// A single ground plane, shown while the real scene loads since the
+    /// acceleration structure can't be built without any geometry.
+    pub fn placeholder() -> Self {
+        let mut scene = Self::empty();
+        scene.verts = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .map(|(x, z)| Vertex {
+                position: Vector3::new(x, 0.0, z) * 10.0,
+                normal: Vector3::y(),
+                uv: Vector2::new(x, z) * 0.5 + Vector2::repeat(0.5),
+            })
+            .to_vec();
+        scene.index = vec![0, 2, 1, 0, 3, 2];
+        scene.meshes.push(Mesh {
+            first_vertex: 0,
+            vertex_count: 4,
+            first_index: 0,
+            index_count: 6,
+        });
+
+        let material = Material::defaults()[0];
+        scene.materials.push(NamedMaterial::new("Ground", material));
+        scene.models.push(Model {
+            name: "Ground".into(),
+            id: next_id(),
+            source: None,
+
+            material: 0,
+            mesh: 0,
+
+            position: Vector3::zeros(),
+            scale: Vector3::repeat(1.0),
+            rotation: UnitQuaternion::identity(),
+            pivot: Vector3::zeros(),
+        });
+        scene
+    }
+
+    /// Moves everything from `other` into this scene, offsetting its indices.
+    pub fn append(&mut self, other: Scene) {
+        let (vertex_offset, index_offset) = (self.verts.len() as u32, self.index.len() as u32);
+        let (mesh_offset, material_offset) =
+            (self.meshes.len() as u32, self.materials.len() as u32);
+        let texture_offset = self.textures.len() as u32;
+
+        self.meshes
+            .extend(other.meshes.into_iter().map(|mesh| Mesh {
+                first_vertex: mesh.first_vertex + vertex_offset,
+                first_index: mesh.first_index + index_offset,
+                ..mesh
+            }));
+        self.models
+            .extend(other.models.into_iter().map(|model| Model {
+                mesh: model.mesh + mesh_offset,
+                material: model.material + material_offset,
+                ..model
+            }));
+        self.materials
+            .extend(other.materials.into_iter().map(|mut material| {
+                // Zero means untextured, so it stays as is
+                for texture in material.material.textures_mut() {
+                    if *texture != 0 {
+                        *texture += texture_offset;
+                    }
+                }
+                material
+            }));
+        self.lights.extend(other.lights);
+        self.textures.extend(other.textures);
+
+        self.verts.extend(other.verts);
+        self.index.extend(other.index);
+        self.sources.extend(other.sources);
+    }
+
+    pub fn finish(&self, gpu: &Gpu) -> Result<SceneBuffers> {
         let vertex = gpu.create_blas(&self.verts)?;
         let index = gpu.create_blas(&self.index)?;
-        let transformation =
-            gpu.create_blas(&vec![Matrix4x3::identity(); self.primitives.len()])?;
-
-        let acceleration = gpu.create_acceleration_structure(
-            vertex.clone(),
-            index.clone(),
-            transformation.clone(),
-            vec![Geometry {
-                transformation: Matrix4::identity(),
-                primitives: mem::take(&mut self.primitives),
-            }],
-        );
+        let (transformation, acceleration) =
+            build_acceleration(gpu, &vertex, &index, &self.meshes, &self.models)?;
 
-        let models = self.models.iter().map(|x| x.to_gpu()).collect::<Vec<_>>();
+        let models = gpu_models(&self.models, &self.meshes);
         let models = gpu.create_storage_read(&models)?;
+        let materials = gpu.create_storage_read(&gpu_materials(&self.materials))?;
+        let lights = gpu.create_storage_read(&gpu_lights(&self.lights))?;
 
         let textures = if self.textures.is_empty() {
             vec![gpu.create_texture_2d(Vector2::new(1, 1))] // shrug
@@ -74,7 +202,7 @@ impl Scene {
                 .map(|image| {
                     let size = Vector2::new(image.width(), image.height());
                     let texture = gpu.create_texture_2d(size);
-                    texture.upload(size, &imageops::flip_vertical(image));
+                    texture.upload(size, &**image);
                     texture
                 })
                 .collect::<Vec<_>>()
@@ -84,6 +212,8 @@ impl Scene {
 
         Ok(SceneBuffers {
             models,
+            materials,
+            lights,
             vertex,
             index,
             transformation,
@@ -92,29 +222,201 @@ impl Scene {
         })
     }
 
-    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<()> {
+    /// Appends a copy of a mesh's vertices and indices, returning the new mesh.
+    pub fn copy_mesh(&mut self, mesh: u32) -> u32 {
+        let mesh = self.meshes[mesh as usize];
+        self.meshes.push(Mesh {
+            first_vertex: self.verts.len() as u32,
+            first_index: self.index.len() as u32,
+            ..mesh
+        });
+        self.verts.extend_from_within(mesh.vertices());
+        // Indices are relative to the first vertex, so they can be copied as is
+        self.index.extend_from_within(mesh.indices());
+
+        self.meshes.len() as u32 - 1
+    }
+
+    /// Drops the meshes no model uses and the textures no material uses,
+    /// along with their vertices and indices. Returns where everything that
+    /// was kept moved to.
+    pub fn compact(&mut self) -> Remap {
+        let mut used = vec![false; self.meshes.len()];
+        self.models
+            .iter()
+            .for_each(|x| used[x.mesh as usize] = true);
+
+        let (mut verts, mut index) = (Vec::new(), Vec::new());
+        let mut remap = vec![None; self.meshes.len()];
+        let mut meshes = Vec::new();
+        for (i, mesh) in self.meshes.iter().enumerate().filter(|(i, _)| used[*i]) {
+            remap[i] = Some(meshes.len() as u32);
+            meshes.push(Mesh {
+                first_vertex: verts.len() as u32,
+                first_index: index.len() as u32,
+                ..*mesh
+            });
+            verts.extend_from_slice(&self.verts[mesh.vertices()]);
+            index.extend_from_slice(&self.index[mesh.indices()]);
+        }
+
+        self.models
+            .iter_mut()
+            .for_each(|x| x.mesh = remap[x.mesh as usize].unwrap());
+        (self.meshes, self.verts, self.index) = (meshes, verts, index);
+        let mesh_remap = remap;
+
+        // Texture ids are offset by one, as zero means untextured
+        let mut used = vec![false; self.textures.len()];
+        for material in self.materials.iter_mut() {
+            for &mut texture in material.material.textures_mut() {
+                if texture != 0 {
+                    used[texture as usize - 1] = true;
+                }
+            }
+        }
+
+        let mut remap = vec![0; self.textures.len() + 1];
+        let mut textures = Vec::new();
+        for (i, texture) in mem::take(&mut self.textures).into_iter().enumerate() {
+            if used[i] {
+                textures.push(texture);
+                remap[i + 1] = textures.len() as u32;
+            }
+        }
+        self.textures = textures;
+
+        for material in self.materials.iter_mut() {
+            for texture in material.material.textures_mut() {
+                *texture = remap[*texture as usize];
+            }
+        }
+
+        Remap {
+            meshes: mesh_remap,
+            textures: remap,
+        }
+    }
+
+    /// Loads either a `.toml` scene file or a single glTF or OBJ file into
+    /// the scene.
+    pub fn load(&mut self, path: impl AsRef<Path>, progress: &Progress) -> Result<()> {
         let path = path.as_ref();
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => self.load_scene_file(path, progress),
+            _ => self.load_models(path, progress),
+        }
+    }
+
+    fn load_models(&mut self, path: &Path, progress: &Progress) -> Result<()> {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("gltf" | "glb") => self.load_gltf(path, progress),
+            _ => self.load_obj(path, progress),
+        }
+    }
+
+    fn load_scene_file(&mut self, path: &Path, progress: &Progress) -> Result<()> {
         let dir = path.parent().unwrap();
-        println!("[*] Loading {path:?}");
-
-        let (obj, materials) = tobj::load_obj(
-            path,
-            &LoadOptions {
-                triangulate: true,
-                single_index: true,
-                ..Default::default()
-            },
-        )?;
+        progress.start(format!("Loading {}", file_name(path)), 0);
+
+        self.sources.push(SourceFile::new(path, None));
+        let scene = toml::from_str::<SceneFile>(&fs::read_to_string(path)?)?;
+        for config in scene.models {
+            let first = self.models.len();
+            self.load_models(&dir.join(config.path()), progress)?;
+            for model in &mut self.models[first..] {
+                config.apply(model);
+            }
+        }
+
+        self.lights
+            .extend(scene.lights.into_iter().map(|x| x.into_light()));
+        Ok(())
+    }
+
+    fn load_obj(&mut self, path: &Path, progress: &Progress) -> Result<()> {
+        let dir = path.parent().unwrap();
+        let name = file_name(path);
+        progress.start(format!("Parsing {name}"), 0);
+
+        let options = LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+        let material_files = RefCell::new(Vec::new());
+        let (obj, materials) = tobj::load_obj_buf(&mut reader, &options, |mtl| {
+            let mtl = dir.join(mtl);
+            material_files.borrow_mut().push(mtl.clone());
+            tobj::load_mtl(mtl)
+        })?;
         let materials = materials?;
 
-        let object_count = obj.len();
-        for (i, model) in obj.into_iter().enumerate() {
-            println!(
-                " {} Loading `{}`",
-                if i + 1 == object_count { "\\" } else { "|" },
-                model.name
-            );
+        let texture_paths = materials
+            .iter()
+            .flat_map(|x| [&x.diffuse_texture, &x.normal_texture])
+            .flatten()
+            .map(|x| dir.join(strip_flags(x)))
+            .collect::<Vec<_>>();
+
+        let files = [path.to_owned()].into_iter();
+        let files = files
+            .chain(material_files.into_inner())
+            .chain(texture_paths.iter().cloned());
+        self.sources
+            .extend(files.map(|file| SourceFile::new(&file, Some(path))));
+        progress.start(format!("Decoding textures for {name}"), texture_paths.len());
+        let mut images =
+            decode_textures(&texture_paths, progress, |x| decode_texture(x))?.into_iter();
+
+        // Models in the file share materials, so they only need to be edited once
+        let material_offset = self.materials.len() as u32;
+        for material in materials {
+            let diffuse = material.diffuse.unwrap_or_default();
+            let specular = material.specular.unwrap_or_default();
+            let specular_probability = material.get_unknown("Pm");
+            let roughness = material.get_unknown("Pr");
+            let emission: Vector3<_> = material.get_unknown("Ke");
 
+            // Decoded in the same order as they are used here
+            let mut load_texture = |path: &Option<String>| {
+                if path.is_some() {
+                    self.textures.push(Arc::new(images.next().unwrap()));
+                    self.textures.len() as u32
+                } else {
+                    0
+                }
+            };
+
+            let diffuse_texture = load_texture(&material.diffuse_texture);
+            let normal_texture = load_texture(&material.normal_texture);
+
+            let metal = Material::Metal(MetalMaterial {
+                diffuse_color: Vector3::from_row_slice(&diffuse),
+                specular_color: Vector3::from_row_slice(&specular),
+
+                specular_probability,
+                roughness,
+
+                emission_color: emission.try_normalize(0.0).unwrap_or_default(),
+                emission_strength: emission.magnitude(),
+
+                diffuse_texture,
+                normal_texture,
+
+                anisotropy: 0.0,
+                anisotropy_rotation: 0.0,
+
+                film_thickness: 0.0,
+                film_refractive_index: 1.33,
+            });
+            self.materials
+                .push(NamedMaterial::new(material.name, metal));
+        }
+
+        progress.start(format!("Building meshes for {name}"), obj.len());
+        for model in obj {
             let (first_index, first_vertex) = (self.index.len(), self.verts.len());
             let mesh = &model.mesh;
 
@@ -138,73 +440,435 @@ impl Scene {
             self.verts.extend(verts);
             self.index.extend_from_slice(&mesh.indices);
 
-            self.primitives.push(GeometryPrimitive {
+            self.meshes.push(Mesh {
                 first_vertex: first_vertex as u32,
                 vertex_count: (self.verts.len() - first_vertex) as u32,
                 first_index: first_index as u32,
                 index_count: (self.index.len() - first_index) as u32,
-                transformation_offset: self.primitives.len() as u64,
             });
 
-            let material = &materials[model.mesh.material_id.unwrap()];
+            self.models.push(Model {
+                name: model.name,
+                id: next_id(),
+                source: Some(path.to_owned()),
 
-            let diffuse = material.diffuse.unwrap_or_default();
-            let specular = material.specular.unwrap_or_default();
-            let specular_probability = material.get_unknown("Pm");
-            let roughness = material.get_unknown("Pr");
-            let emission: Vector3<_> = material.get_unknown("Ke");
+                material: material_offset + model.mesh.material_id.unwrap() as u32,
+                mesh: self.meshes.len() as u32 - 1,
 
-            let mut load_texture = |path: &Option<String>| {
-                if let Some(file) = path {
-                    let path = dir.join(strip_flags(file));
-                    let file = BufReader::new(File::open(&path).unwrap());
-                    let format = ImageFormat::from_path(&path).unwrap();
-
-                    let image = match image::load(file, format) {
-                        Result::Ok(x) => x.into_rgba8(),
-                        Result::Err(e) => {
-                            println!("While loading {path:?}");
-                            panic!("{e}");
-                        }
-                    };
-                    self.textures.push(image);
-                    self.textures.len() as u32
-                } else {
-                    0
+                position: Vector3::zeros(),
+                scale: Vector3::repeat(1.0),
+                rotation: UnitQuaternion::identity(),
+                pivot: Vector3::zeros(),
+            });
+            progress.step();
+        }
+
+        Ok(())
+    }
+
+    fn load_gltf(&mut self, path: &Path, progress: &Progress) -> Result<()> {
+        let dir = path.parent().unwrap();
+        let name = file_name(path);
+        progress.start(format!("Parsing {name}"), 0);
+
+        let Gltf { document, blob } = Gltf::open(path)?;
+        let buffers = gltf::import_buffers(&document, Some(dir), blob)?;
+
+        // Embedded buffers and images are part of the file itself
+        let buffer_uris = document.buffers().filter_map(|x| match x.source() {
+            buffer::Source::Uri(uri) => Some(uri),
+            buffer::Source::Bin => None,
+        });
+        let image_uris = document.images().filter_map(|x| match x.source() {
+            gltf::image::Source::Uri { uri, .. } => Some(uri),
+            gltf::image::Source::View { .. } => None,
+        });
+        let files = (buffer_uris.chain(image_uris))
+            .filter(|x| !x.starts_with("data:"))
+            .map(|x| dir.join(urlencoding::decode(x).as_deref().unwrap_or(x)));
+        let files = [path.to_owned()].into_iter().chain(files);
+        self.sources
+            .extend(files.map(|file| SourceFile::new(&file, Some(path))));
+
+        // Only images used by a material are decoded
+        let mut images = Vec::<gltf::Image>::new();
+        for material in document.materials() {
+            for texture in gltf_textures(&material).into_iter().flatten() {
+                let image = texture.source();
+                if !images.iter().any(|x| x.index() == image.index()) {
+                    images.push(image);
                 }
-            };
+            }
+        }
 
-            let diffuse_texture = load_texture(&material.diffuse_texture);
-            let normal_texture = load_texture(&material.normal_texture);
+        progress.start(format!("Decoding textures for {name}"), images.len());
+        let decoded = decode_textures(&images, progress, |image| {
+            let data = gltf::image::Data::from_source(image.source(), Some(dir), &buffers);
+            gltf_image(data?).with_context(|| format!("Decoding image {} of {name}", image.index()))
+        })?;
+
+        let texture_offset = self.textures.len() as u32;
+        self.textures.extend(decoded.into_iter().map(Arc::new));
+        let texture_id = |texture: Option<gltf::Texture>| {
+            texture.map_or(0, |texture| {
+                let image = texture.source().index();
+                let i = images.iter().position(|x| x.index() == image).unwrap();
+                texture_offset + i as u32 + 1
+            })
+        };
 
-            self.models.push(Model {
-                name: model.name,
-                id: next_id(),
+        // Models in the file share materials, so they only need to be edited once.
+        // Primitives without one use the default material, added once needed.
+        let material_offset = self.materials.len() as u32;
+        for (i, material) in document.materials().enumerate() {
+            let [diffuse, normal] = gltf_textures(&material);
+            let material_name =
+                (material.name()).map_or_else(|| format!("{name} {i}"), String::from);
+            let material = gltf_material(&material, texture_id(diffuse), texture_id(normal));
+            self.materials
+                .push(NamedMaterial::new(material_name, material));
+        }
+        let mut default_material = None;
+
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next());
+        let scene = scene.context("No scenes in file")?;
+        progress.start(
+            format!("Building meshes for {name}"),
+            document.nodes().len(),
+        );
 
-                material: Material::metal(MetalMaterial {
-                    diffuse_color: Vector3::from_row_slice(&diffuse),
-                    specular_color: Vector3::from_row_slice(&specular),
+        // Nodes are placed by their parents' transforms, but shear from a
+        // non-uniform scale under a rotation can't be represented and is lost
+        let root = (
+            Vector3::zeros(),
+            UnitQuaternion::identity(),
+            Vector3::repeat(1.0),
+        );
+        let mut stack = (scene.nodes().map(|x| (x, root))).collect::<Vec<_>>();
+        stack.reverse();
+
+        // Meshes used by more than one node are only added once, so the
+        // models share their vertices and indices
+        let mut meshes = HashMap::new();
+        while let Some((node, (parent_position, parent_rotation, parent_scale))) = stack.pop() {
+            let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+            let position = parent_position
+                + parent_rotation * parent_scale.component_mul(&Vector3::from(translation));
+            let rotation =
+                parent_rotation * UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z));
+            let scale = parent_scale.component_mul(&Vector3::from(scale));
+
+            let children = node.children().collect::<Vec<_>>();
+            let transform = (position, rotation, scale);
+            stack.extend(children.into_iter().rev().map(|x| (x, transform)));
+            progress.step();
+
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+            let model_name = (node.name().or(mesh.name()))
+                .map_or_else(|| format!("{name} {}", node.index()), String::from);
 
-                    specular_probability,
-                    roughness,
+            for primitive in mesh.primitives() {
+                if primitive.mode() != Mode::Triangles {
+                    continue;
+                }
 
-                    emission_color: emission.try_normalize(0.0).unwrap_or_default(),
-                    emission_strength: emission.magnitude(),
+                let key = (mesh.index(), primitive.index());
+                let mesh = match meshes.get(&key) {
+                    Some(&mesh) => mesh,
+                    None => {
+                        let mesh = self.load_gltf_primitive(&primitive, &buffers)?;
+                        *meshes.entry(key).or_insert(mesh)
+                    }
+                };
+
+                let material = match primitive.material().index() {
+                    Some(i) => material_offset + i as u32,
+                    None => *default_material.get_or_insert_with(|| {
+                        let material = gltf_material(&primitive.material(), 0, 0);
+                        self.materials.push(NamedMaterial::new("Default", material));
+                        self.materials.len() as u32 - 1
+                    }),
+                };
+
+                self.models.push(Model {
+                    name: model_name.clone(),
+                    id: next_id(),
+                    source: Some(path.to_owned()),
+
+                    material,
+                    mesh,
+
+                    position,
+                    scale,
+                    rotation,
+                    pivot: Vector3::zeros(),
+                });
+            }
+        }
 
-                    diffuse_texture,
-                    normal_texture,
-                }),
-                vertex_start: first_vertex as u32,
-                index_start: first_index as u32,
+        Ok(())
+    }
 
-                position: Vector3::zeros(),
-                scale: Vector3::repeat(1.0),
-                rotation: Vector3::repeat(0.0),
+    /// Adds the vertices and indices of a triangle list, returning the new mesh.
+    fn load_gltf_primitive(
+        &mut self,
+        primitive: &gltf::Primitive,
+        buffers: &[buffer::Data],
+    ) -> Result<u32> {
+        let reader = primitive.reader(|x| buffers.get(x.index()).map(|x| &x[..]));
+        let positions = (reader.read_positions())
+            .context("Mesh without vertex positions")?
+            .collect::<Vec<_>>();
+        let index = match reader.read_indices() {
+            Some(index) => index.into_u32().collect(),
+            None => (0..positions.len() as u32).collect::<Vec<_>>(),
+        };
+        let normals = match reader.read_normals() {
+            Some(normals) => normals.collect(),
+            None => vertex_normals(&positions, &index),
+        };
+        let mut uvs = reader.read_tex_coords(0).map(|x| x.into_f32());
+
+        let first_vertex = self.verts.len() as u32;
+        let first_index = self.index.len() as u32;
+        for (position, normal) in positions.into_iter().zip(normals) {
+            // glTF puts the origin of texture coordinates at the top left
+            let [u, v] = uvs.as_mut().and_then(Iterator::next).unwrap_or_default();
+            self.verts.push(Vertex {
+                position: Vector3::from(position),
+                normal: Vector3::from(normal),
+                uv: Vector2::new(u, 1.0 - v),
             });
         }
+        self.index.extend(index);
+
+        self.meshes.push(Mesh {
+            first_vertex,
+            vertex_count: self.verts.len() as u32 - first_vertex,
+            first_index,
+            index_count: self.index.len() as u32 - first_index,
+        });
+        Ok(self.meshes.len() as u32 - 1)
+    }
+}
 
-        Ok(())
+/// Builds the acceleration structure with one primitive per model, so models
+/// that are instances of the same mesh share its vertices and indices. Each
+/// primitive gets its own slot in the returned transform buffer.
+///
+/// The BVH itself is still built over every primitive, so its memory grows
+/// with the number of instances. `compute` places each [`Geometry`] as a
+/// single TLAS instance of its own BLAS, and has no way yet to place one
+/// mesh's BLAS several times.
+pub fn build_acceleration(
+    gpu: &Gpu,
+    vertex: &BlasBuffer<Vertex>,
+    index: &BlasBuffer<u32>,
+    meshes: &[Mesh],
+    models: &[Model],
+) -> Result<(TransformBuffer, AccelerationStructure<Vertex>)> {
+    let transformation = gpu.create_blas(&vec![Matrix4x3::identity(); models.len()])?;
+    let primitives = models
+        .iter()
+        .enumerate()
+        .map(|(i, model)| {
+            let mesh = meshes[model.mesh as usize];
+            GeometryPrimitive {
+                first_vertex: mesh.first_vertex,
+                vertex_count: mesh.vertex_count,
+                first_index: mesh.first_index,
+                index_count: mesh.index_count,
+                transformation_offset: i as u64,
+            }
+        })
+        .collect();
+
+    let acceleration = gpu.create_acceleration_structure(
+        vertex.clone(),
+        index.clone(),
+        transformation.clone(),
+        vec![Geometry {
+            transformation: Matrix4::identity(),
+            primitives,
+        }],
+    );
+
+    Ok((transformation, acceleration))
+}
+
+pub fn gpu_models(models: &[Model], meshes: &[Mesh]) -> Vec<GpuModel> {
+    models.iter().map(|x| x.to_gpu(meshes)).collect()
+}
+
+/// Storage buffers can't be empty, so a scene without materials gets a
+/// default one.
+pub fn gpu_materials(materials: &[NamedMaterial]) -> Vec<GpuMaterial> {
+    if materials.is_empty() {
+        return vec![Material::defaults()[0].to_gpu()];
     }
+
+    materials.iter().map(|x| x.material.to_gpu()).collect()
+}
+
+/// Disabled lights are left out, and an empty list is padded with a light
+/// that emits nothing as storage buffers can't be empty.
+pub fn gpu_lights(lights: &[Light]) -> Vec<GpuLight> {
+    let lights = lights
+        .iter()
+        .filter(|x| x.enabled)
+        .map(|x| x.to_gpu())
+        .collect::<Vec<_>>();
+
+    if lights.is_empty() {
+        vec![GpuLight::default()]
+    } else {
+        lights
+    }
+}
+
+/// Decodes images split across a thread per core, keeping them in order.
+fn decode_textures<T: Sync>(
+    sources: &[T],
+    progress: &Progress,
+    decode: impl Fn(&T) -> Result<RgbaImage> + Sync,
+) -> Result<Vec<RgbaImage>> {
+    let threads = thread::available_parallelism().map_or(4, |x| x.get());
+    let chunk_size = sources.len().div_ceil(threads).max(1);
+
+    let decode = &decode;
+    thread::scope(|s| {
+        let workers = sources
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|source| {
+                            let image = decode(source);
+                            progress.step();
+                            image
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .flat_map(|x| x.join().unwrap())
+            .collect()
+    })
+}
+
+fn decode_texture(path: &Path) -> Result<RgbaImage> {
+    let file = BufReader::new(File::open(path).with_context(|| format!("Opening {path:?}"))?);
+    let format = ImageFormat::from_path(path)?;
+    let image = image::load(file, format).with_context(|| format!("Decoding {path:?}"))?;
+    Ok(imageops::flip_vertical(&image.into_rgba8()))
+}
+
+/// The base color and normal textures of a glTF material.
+fn gltf_textures<'a>(material: &gltf::Material<'a>) -> [Option<gltf::Texture<'a>>; 2] {
+    let diffuse = material.pbr_metallic_roughness().base_color_texture();
+    let normal = material.normal_texture();
+    [diffuse.map(|x| x.texture()), normal.map(|x| x.texture())]
+}
+
+/// Converts a glTF metallic roughness material. Metals tint their
+/// reflections with the base color, while dielectrics reflect white.
+fn gltf_material(material: &gltf::Material, diffuse_texture: u32, normal_texture: u32) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, _] = pbr.base_color_factor();
+    let base_color = Vector3::new(r, g, b);
+    let metallic = pbr.metallic_factor();
+    let emission = Vector3::from(material.emissive_factor());
+
+    Material::Metal(MetalMaterial {
+        diffuse_color: base_color,
+        specular_color: Vector3::repeat(1.0).lerp(&base_color, metallic),
+
+        specular_probability: metallic,
+        roughness: pbr.roughness_factor(),
+
+        emission_color: emission.try_normalize(0.0).unwrap_or_default(),
+        emission_strength: emission.magnitude(),
+
+        diffuse_texture,
+        normal_texture,
+
+        anisotropy: 0.0,
+        anisotropy_rotation: 0.0,
+
+        film_thickness: 0.0,
+        film_refractive_index: 1.33,
+    })
+}
+
+/// Converts an image from the glTF importer to RGBA, flipped like the
+/// textures of OBJ files.
+fn gltf_image(data: gltf::image::Data) -> Result<RgbaImage> {
+    let (width, height, pixels) = (data.width, data.height, data.pixels);
+    // Wider channels are in native byte order
+    let wide = || {
+        let wide = pixels
+            .chunks_exact(2)
+            .map(|x| u16::from_ne_bytes([x[0], x[1]]));
+        wide.collect()
+    };
+    let float = || {
+        let float = pixels
+            .chunks_exact(4)
+            .map(|x| f32::from_ne_bytes(x.try_into().unwrap()));
+        float.collect()
+    };
+
+    let image = match data.format {
+        Format::R8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        Format::R8G8 => GrayAlphaImage::from_raw(width, height, pixels).map(From::from),
+        Format::R8G8B8 => RgbImage::from_raw(width, height, pixels).map(From::from),
+        Format::R8G8B8A8 => RgbaImage::from_raw(width, height, pixels).map(From::from),
+        Format::R16 => ImageBuffer::<Luma<u16>, _>::from_raw(width, height, wide()).map(From::from),
+        Format::R16G16 => {
+            ImageBuffer::<LumaA<u16>, _>::from_raw(width, height, wide()).map(From::from)
+        }
+        Format::R16G16B16 => {
+            ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, wide()).map(From::from)
+        }
+        Format::R16G16B16A16 => {
+            ImageBuffer::<Rgba<u16>, _>::from_raw(width, height, wide()).map(From::from)
+        }
+        Format::R32G32B32FLOAT => Rgb32FImage::from_raw(width, height, float()).map(From::from),
+        Format::R32G32B32A32FLOAT => Rgba32FImage::from_raw(width, height, float()).map(From::from),
+    };
+
+    let image: DynamicImage = image.context("Invalid texture size")?;
+    Ok(imageops::flip_vertical(&image.into_rgba8()))
+}
+
+/// Smooth normals for meshes that don't have their own, averaging the faces
+/// around each vertex weighted by their area.
+fn vertex_normals(positions: &[[f32; 3]], index: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![Vector3::zeros(); positions.len()];
+    for face in index.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| Vector3::from(positions[face[i] as usize]));
+        let normal = (b - a).cross(&(c - a));
+        face.iter().for_each(|&i| normals[i as usize] += normal);
+    }
+
+    (normals.into_iter())
+        .map(|x| x.try_normalize(0.0).unwrap_or_else(Vector3::y).into())
+        .collect()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |x| x.to_string_lossy().into(),
+    )
 }
 
 fn strip_flags(path: &str) -> &str {
@@ -221,3 +885,61 @@ fn strip_flags(path: &str) -> &str {
 
     &path[i..]
 }
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use compute::export::nalgebra::Vector3;
+
+    use super::Scene;
+    use crate::loader::Progress;
+
+    /// A triangle without normals used by two nodes under a scaled parent.
+    const INSTANCES: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [
+            { "name": "Parent", "translation": [1, 0, 0], "scale": [2, 2, 2], "children": [1, 2] },
+            { "name": "A", "mesh": 0, "translation": [0, 1, 0] },
+            { "name": "B", "mesh": 0 }
+        ],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }],
+        "accessors": [{
+            "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+            "min": [0, 0, 0], "max": [1, 1, 0]
+        }],
+        "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+        "buffers": [{
+            "byteLength": 36,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"
+        }]
+    }"#;
+
+    #[test]
+    fn gltf_instances() {
+        let path = env::temp_dir().join(format!("ray-tracing-gltf-{}.gltf", std::process::id()));
+        fs::write(&path, INSTANCES).unwrap();
+        let mut scene = Scene::empty();
+        let loaded = scene.load(&path, &Progress::default());
+        fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+
+        assert_eq!(scene.meshes.len(), 1);
+        let models = (scene.models.iter())
+            .map(|x| (x.name.as_str(), x.mesh, x.position, x.scale))
+            .collect::<Vec<_>>();
+        let scale = Vector3::repeat(2.0);
+        assert_eq!(
+            models,
+            [
+                ("A", 0, Vector3::new(1.0, 2.0, 0.0), scale),
+                ("B", 0, Vector3::x(), scale)
+            ]
+        );
+
+        assert!(scene.verts.iter().all(|x| x.normal == Vector3::z()));
+        assert_eq!(scene.materials[0].name, "Default");
+    }
+}
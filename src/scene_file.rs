@@ -0,0 +1,167 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use compute::export::nalgebra::{Quaternion, UnitQuaternion, Vector2, Vector3};
+use serde::Deserialize;
+
+use crate::types::{EulerOrder, Light, LightKind, Model};
+
+/// A TOML description of a scene, listing the model files to load along with
+/// anything that can't be expressed in them.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    /// OBJ or glTF files relative to the scene file, either as a plain path
+    /// or a table with a transform applied to every model in the file.
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    #[serde(default)]
+    pub lights: Vec<LightConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ModelConfig {
+    Path(PathBuf),
+    Transformed(TransformedModel),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformedModel {
+    path: PathBuf,
+    position: Option<[f32; 3]>,
+    scale: Option<[f32; 3]>,
+    rotation: Option<RotationConfig>,
+    pivot: Option<[f32; 3]>,
+}
+
+/// Rotations are either `{ euler = [x, y, z], order = "xyz" }` in degrees or
+/// `{ quaternion = [x, y, z, w] }`, matching the component order of glTF.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum RotationConfig {
+    Euler {
+        euler: [f32; 3],
+        #[serde(default)]
+        order: EulerOrder,
+    },
+    Quaternion {
+        quaternion: [f32; 4],
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightConfig {
+    name: Option<String>,
+    #[serde(rename = "type")]
+    kind: LightKindConfig,
+    enabled: Option<bool>,
+
+    color: Option<[f32; 3]>,
+    intensity: Option<f32>,
+
+    position: Option<[f32; 3]>,
+    direction: Option<[f32; 3]>,
+    radius: Option<f32>,
+    size: Option<[f32; 2]>,
+    cone: Option<[f32; 2]>,
+    angular_diameter: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LightKindConfig {
+    Point,
+    Spot,
+    Directional,
+    Rectangle,
+    Disk,
+}
+
+impl SceneFile {
+    /// Finds the entry for the OBJ at `obj` in the scene file at `path`, None
+    /// if it's no longer listed or the file can't be read.
+    pub fn find_model(path: &Path, obj: &Path) -> Option<ModelConfig> {
+        let scene = toml::from_str::<SceneFile>(&fs::read_to_string(path).ok()?).ok()?;
+        let dir = path.parent()?;
+        (scene.models.into_iter()).find(|x| dir.join(x.path()) == obj)
+    }
+}
+
+impl ModelConfig {
+    pub fn path(&self) -> &Path {
+        match self {
+            ModelConfig::Path(path) => path,
+            ModelConfig::Transformed(model) => &model.path,
+        }
+    }
+
+    pub fn apply(&self, model: &mut Model) {
+        let ModelConfig::Transformed(config) = self else {
+            return;
+        };
+
+        if let Some(position) = config.position {
+            model.position = Vector3::from(position);
+        }
+        if let Some(scale) = config.scale {
+            model.scale = Vector3::from(scale);
+        }
+        if let Some(rotation) = &config.rotation {
+            model.rotation = rotation.to_quaternion();
+        }
+        if let Some(pivot) = config.pivot {
+            model.pivot = Vector3::from(pivot);
+        }
+    }
+}
+
+impl RotationConfig {
+    pub fn to_quaternion(&self) -> UnitQuaternion<f32> {
+        match *self {
+            RotationConfig::Euler { euler, order } => order.compose(Vector3::from(euler)),
+            RotationConfig::Quaternion {
+                quaternion: [x, y, z, w],
+            } => UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)),
+        }
+    }
+}
+
+impl LightConfig {
+    pub fn into_light(self) -> Light {
+        let kind = match self.kind {
+            LightKindConfig::Point => LightKind::Point,
+            LightKindConfig::Spot => LightKind::Spot,
+            LightKindConfig::Directional => LightKind::Directional,
+            LightKindConfig::Rectangle => LightKind::Rectangle,
+            LightKindConfig::Disk => LightKind::Disk,
+        };
+
+        let default = Light::new(kind);
+        Light {
+            name: self.name.unwrap_or(default.name),
+            enabled: self.enabled.unwrap_or(default.enabled),
+
+            color: self.color.map(Vector3::from).unwrap_or(default.color),
+            intensity: self.intensity.unwrap_or(default.intensity),
+
+            position: self.position.map(Vector3::from).unwrap_or(default.position),
+            direction: self
+                .direction
+                .map(Vector3::from)
+                .unwrap_or(default.direction),
+            radius: self.radius.unwrap_or(default.radius),
+            size: self.size.map(Vector2::from).unwrap_or(default.size),
+            // The inner angle is kept within the outer one
+            cone: (self.cone)
+                .map(|[inner, outer]| Vector2::new(inner.min(outer), outer))
+                .unwrap_or(default.cone),
+            angular_diameter: self.angular_diameter.unwrap_or(default.angular_diameter),
+            ..default
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    borrow::Cow,
+    fs,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use compute::export::wgpu::{ShaderModuleDescriptor, ShaderSource};
+use naga::{
+    front::wgsl,
+    valid::{Capabilities, ValidationFlags, Validator},
+    AddressSpace, GlobalVariable, Handle, Module, ScalarKind, StorageAccess, Type, TypeInner,
+};
+
+use crate::{
+    camera::Camera,
+    consts::{SHADER_DIR, SHADER_FILES},
+    sky::Sky,
+    types::{
+        Aov, Denoise, Fog, GpuLight, GpuMaterial, GpuModel, Pixel, ShaderFeatures, Uniform, Vertex,
+        VolumeMaterial,
+    },
+    wgsl::{preprocess, WgslStruct},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Name the generated struct definitions can be included with.
+const STRUCTS_FILE: &str = "structs.wgsl";
+
+/// What each pipeline in `app.rs` binds, in binding order. Written the way
+/// [`describe`] prints a shader's bindings.
+const COMPUTE_BINDINGS: &[&str] = &[
+    "uniform Uniform",
+    "storage read_write array<Pixel>",
+    "storage read array<Model>",
+    "acceleration_structure",
+    "storage read array<Vertex>",
+    "storage read array<u32>",
+    "sampler",
+    "binding_array<texture>",
+    "storage read_write array<Aov>",
+    "storage read_write array<vec2u>",
+    "storage read array<Light>",
+    "storage read array<Material>",
+];
+const DENOISE_BINDINGS: &[&str] = &[
+    "uniform Uniform",
+    "uniform Denoise",
+    "storage read_write array<Pixel>",
+    "storage read_write array<Aov>",
+    "storage read_write array<vec3f>",
+    "storage read_write array<vec3f>",
+];
+const RENDER_BINDINGS: &[&str] = &[
+    "uniform Uniform",
+    "storage read_write array<Pixel>",
+    "uniform Denoise",
+    "storage read_write array<vec3f>",
+    "storage read_write array<vec3f>",
+    "storage read_write array<Aov>",
+];
+
+/// Shader sources for the pipelines. Release builds use the shaders embedded
+/// at compile time, while debug builds read them from `shaders/` and reload
+/// them whenever they're saved.
+pub struct Shaders {
+    pub compute: ShaderModuleDescriptor<'static>,
+    pub denoise: ShaderModuleDescriptor<'static>,
+    pub render: ShaderModuleDescriptor<'static>,
+    pub features: ShaderFeatures,
+    /// Compile error from the last rebuild. The previous sources are kept
+    /// until the error is fixed.
+    pub error: Option<String>,
+
+    hot_reload: bool,
+    modified: Option<SystemTime>,
+    last_poll: Instant,
+}
+
+impl Shaders {
+    pub fn new(features: ShaderFeatures) -> Self {
+        let [compute, denoise, render] =
+            compile(false, features).unwrap_or_else(|err| panic!("Embedded shaders: {err}"));
+
+        let mut shaders = Self {
+            compute,
+            denoise,
+            render,
+            features,
+            error: None,
+
+            hot_reload: cfg!(debug_assertions) && Path::new(SHADER_DIR).is_dir(),
+            modified: None,
+            last_poll: Instant::now(),
+        };
+
+        if shaders.hot_reload {
+            shaders.modified = last_modified();
+            shaders.rebuild();
+        }
+
+        shaders
+    }
+
+    /// Checks if any shader file changed and rebuilds them if so. Returns
+    /// true if new sources were loaded and the pipelines need to be recreated.
+    pub fn poll(&mut self) -> bool {
+        if !self.hot_reload || self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        let modified = last_modified();
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        self.rebuild()
+    }
+
+    /// Compiles the shaders with the current features, returning true on
+    /// success.
+    pub fn rebuild(&mut self) -> bool {
+        match compile(self.hot_reload, self.features) {
+            Ok([compute, denoise, render]) => {
+                (self.compute, self.denoise, self.render) = (compute, denoise, render);
+                self.error = None;
+                true
+            }
+            Err(err) => {
+                println!("[!] Failed to build shaders:\n{err}");
+                self.error = Some(err);
+                false
+            }
+        }
+    }
+}
+
+/// The WGSL definitions of every struct shared with the shaders.
+fn structs() -> String {
+    let structs = [
+        Uniform::wgsl_definition(),
+        Camera::wgsl_definition(),
+        Sky::wgsl_definition(),
+        Fog::wgsl_definition(),
+        Denoise::wgsl_definition(),
+        Pixel::wgsl_definition(),
+        Aov::wgsl_definition(),
+        GpuMaterial::wgsl_definition(),
+        VolumeMaterial::wgsl_definition(),
+        GpuModel::wgsl_definition(),
+        GpuLight::wgsl_definition(),
+        Vertex::wgsl_definition(),
+    ];
+    format!("// Generated from the Rust types\n\n{}", structs.join("\n"))
+}
+
+fn compile(
+    from_disk: bool,
+    features: ShaderFeatures,
+) -> Result<[ShaderModuleDescriptor<'static>; 3], String> {
+    let defines = features
+        .iter_names()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    let structs = structs();
+    let read = |file: &str| -> Result<String> {
+        if file == STRUCTS_FILE {
+            return Ok(structs.clone());
+        }
+
+        if from_disk {
+            let path = Path::new(SHADER_DIR).join(file);
+            return fs::read_to_string(&path).with_context(|| format!("Reading {path:?}"));
+        }
+
+        let source = SHADER_FILES.iter().find(|(name, _)| *name == file);
+        source
+            .map(|(_, source)| source.to_string())
+            .with_context(|| format!("No shader named {file}"))
+    };
+
+    Ok([
+        load("main.wgsl", COMPUTE_BINDINGS, &defines, &read)?,
+        load("denoise.wgsl", DENOISE_BINDINGS, &defines, &read)?,
+        load("render.wgsl", RENDER_BINDINGS, &defines, &read)?,
+    ])
+}
+
+/// Preprocesses a shader and validates the result, so errors can be shown
+/// instead of wgpu panicking when creating the pipeline.
+fn load(
+    entry: &'static str,
+    bindings: &[&str],
+    defines: &[&str],
+    read: &dyn Fn(&str) -> Result<String>,
+) -> Result<ShaderModuleDescriptor<'static>, String> {
+    let source = preprocess(entry, defines, read).map_err(|err| format!("{err:#}"))?;
+
+    let module = wgsl::parse_str(&source).map_err(|err| err.emit_to_string(&source))?;
+    Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|err| err.emit_to_string(&source))?;
+    check_bindings(&module, bindings).map_err(|err| format!("{entry}: {err}"))?;
+
+    Ok(ShaderModuleDescriptor {
+        label: Some(entry),
+        source: ShaderSource::Wgsl(Cow::Owned(source)),
+    })
+}
+
+/// A valid shader can still declare bindings that don't match the pipeline
+/// layout, which wgpu panics on, so they're compared with what's bound.
+fn check_bindings(module: &Module, expected: &[&str]) -> Result<(), String> {
+    let mut used = vec![false; expected.len()];
+    for (_, var) in module.global_variables.iter() {
+        let Some(binding) = &var.binding else {
+            continue;
+        };
+
+        let name = var.name.as_deref().unwrap_or_default();
+        let (index, actual) = (binding.binding as usize, describe(module, var));
+        match expected.get(index) {
+            _ if binding.group != 0 => {
+                return Err(format!(
+                    "`{name}` is in group {}, only group 0 is bound",
+                    binding.group
+                ))
+            }
+            None => return Err(format!("`{name}` uses binding {index}, which isn't bound")),
+            Some(&x) if x != actual => {
+                return Err(format!(
+                    "`{name}` at binding {index} is `{actual}`, but `{x}` is bound there"
+                ))
+            }
+            Some(_) => used[index] = true,
+        }
+    }
+
+    match used.iter().position(|x| !x) {
+        Some(index) => Err(format!(
+            "Binding {index} (`{}`) is missing",
+            expected[index]
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Describes a binding's resource in a WGSL like form.
+fn describe(module: &Module, var: &GlobalVariable) -> String {
+    match (var.space, &module.types[var.ty].inner) {
+        (AddressSpace::Uniform, _) => format!("uniform {}", type_name(module, var.ty)),
+        (AddressSpace::Storage { access }, _) => {
+            let access = if access.contains(StorageAccess::STORE) {
+                "read_write"
+            } else {
+                "read"
+            };
+            format!("storage {access} {}", type_name(module, var.ty))
+        }
+        (_, TypeInner::AccelerationStructure { .. }) => "acceleration_structure".into(),
+        (_, TypeInner::Sampler { .. }) => "sampler".into(),
+        _ => type_name(module, var.ty),
+    }
+}
+
+fn type_name(module: &Module, ty: Handle<Type>) -> String {
+    let ty = &module.types[ty];
+    let kind = |kind: ScalarKind| match kind {
+        ScalarKind::Uint => "u",
+        ScalarKind::Sint => "i",
+        _ => "f",
+    };
+
+    match ty.inner {
+        TypeInner::Scalar(x) => format!("{}{}", kind(x.kind), x.width * 8),
+        TypeInner::Vector { size, scalar } => format!("vec{}{}", size as u8, kind(scalar.kind)),
+        TypeInner::Array { base, .. } => format!("array<{}>", type_name(module, base)),
+        TypeInner::BindingArray { base, .. } => {
+            format!("binding_array<{}>", type_name(module, base))
+        }
+        TypeInner::Image { .. } => "texture".into(),
+        _ => ty.name.clone().unwrap_or_default(),
+    }
+}
+
+fn last_modified() -> Option<SystemTime> {
+    let entries = fs::read_dir(SHADER_DIR).ok()?;
+    entries
+        .filter_map(|x| x.ok()?.metadata().ok()?.modified().ok())
+        .max()
+}
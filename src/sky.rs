@@ -0,0 +1,218 @@
+use std::{
+    f32::consts::{FRAC_PI_2, PI, TAU},
+    hash::{Hash, Hasher},
+};
+
+use compute::export::{
+    egui::{DragValue, Slider, Ui},
+    nalgebra::Vector3,
+};
+use ordered_float::OrderedFloat;
+
+use crate::{misc::color_edit, wgsl::wgsl_struct};
+
+wgsl_struct! {
+    /// Coefficients for the Preetham sky model, precomputed on the CPU so the
+    /// shader only has to evaluate the Perez distribution.
+    #[derive(Default, Clone)]
+    pub struct Sky {
+        pub sun_direction: Vector3<f32>,
+        pub sun_cos_radius: f32,
+        /// Sun irradiance after atmospheric extinction.
+        pub sun_irradiance: Vector3<f32>,
+        pub ground_albedo: Vector3<f32>,
+
+        /// Luminance and chromaticity (Y, x, y) at the zenith.
+        pub zenith: Vector3<f32>,
+        /// Perez coefficients A through E for each of Y, x and y.
+        pub perez: [Vector3<f32>; 5],
+        /// The Perez function evaluated at the zenith, used for normalization.
+        pub perez_zenith: Vector3<f32>,
+    }
+}
+
+#[derive(Clone)]
+pub struct SkySettings {
+    /// Local solar time in hours.
+    pub time_of_day: f32,
+    /// Latitude in degrees.
+    pub latitude: f32,
+    pub day_of_year: u32,
+    pub turbidity: f32,
+    pub ground_albedo: Vector3<f32>,
+    /// Sun irradiance in W/m² before atmospheric extinction.
+    pub sun_intensity: f32,
+    /// Apparent diameter of the sun in degrees.
+    pub sun_diameter: f32,
+}
+
+impl SkySettings {
+    /// Direction towards the sun, with +Y up, -Z north and +X east.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let latitude = self.latitude.to_radians();
+        let declination =
+            -23.44_f32.to_radians() * (TAU / 365.0 * (self.day_of_year as f32 + 10.0)).cos();
+        let hour_angle = (15.0 * (self.time_of_day - 12.0)).to_radians();
+
+        let elevation = (latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * hour_angle.cos())
+        .clamp(-1.0, 1.0)
+        .asin();
+
+        let cos_azimuth = ((declination.sin() - elevation.sin() * latitude.sin())
+            / (elevation.cos() * latitude.cos()).max(1e-6))
+        .clamp(-1.0, 1.0);
+        let mut azimuth = cos_azimuth.acos();
+        if hour_angle > 0.0 {
+            azimuth = TAU - azimuth;
+        }
+
+        Vector3::new(
+            azimuth.sin() * elevation.cos(),
+            elevation.sin(),
+            -azimuth.cos() * elevation.cos(),
+        )
+    }
+
+    // From "A Practical Analytic Model for Daylight" (Preetham et al., 1999)
+    pub fn to_gpu(&self) -> Sky {
+        let t = self.turbidity;
+        let sun_direction = self.sun_direction();
+        // The model breaks down once the sun is below the horizon
+        let theta = sun_direction.y.clamp(0.0, 1.0).acos().min(FRAC_PI_2 - 0.01);
+
+        let perez = [
+            Vector3::new(
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+            ),
+            Vector3::new(
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+            ),
+            Vector3::new(
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+            ),
+            Vector3::new(
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+            ),
+            Vector3::new(
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+            ),
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (theta2, theta3) = (theta * theta, theta * theta * theta);
+        let x = t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+            + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+        let y = t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+            + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+
+        let perez_zenith = Vector3::from_fn(|i, _| {
+            let [a, b, c, d, e] = perez.map(|x| x[i]);
+            (1.0 + a * b.exp()) * (1.0 + c * (d * theta).exp() + e * theta.cos().powi(2))
+        });
+
+        // Rough atmospheric extinction, so the sun reddens towards the horizon
+        let elevation = 90.0 - theta.to_degrees();
+        let air_mass = 1.0 / (theta.cos() + 0.50572 * (elevation + 6.07995).powf(-1.6364));
+        let extinction = Vector3::new(0.008, 0.016, 0.036) * t * air_mass;
+        let visible = sun_direction.y > 0.0;
+
+        Sky {
+            sun_direction,
+            sun_cos_radius: (self.sun_diameter / 2.0).to_radians().cos(),
+            sun_irradiance: extinction.map(|x| (-x).exp())
+                * self.sun_intensity
+                * visible as u8 as f32,
+            ground_albedo: self.ground_albedo,
+
+            // Luminance is normalized to the zenith, brightness is controlled by the environment slider
+            zenith: Vector3::new(1.0, x, y) * (luminance > 0.0) as u8 as f32,
+            perez,
+            perez_zenith,
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.add(Slider::new(&mut self.time_of_day, 0.0..=24.0).suffix("h"));
+            ui.label("Time of Day");
+        });
+
+        ui.horizontal(|ui| {
+            ui.add(Slider::new(&mut self.latitude, -90.0..=90.0).suffix("°"));
+            ui.label("Latitude");
+        });
+
+        ui.horizontal(|ui| {
+            ui.add(Slider::new(&mut self.day_of_year, 1..=365));
+            ui.label("Day of Year");
+        });
+
+        ui.horizontal(|ui| {
+            ui.add(Slider::new(&mut self.turbidity, 1.7..=10.0));
+            ui.label("Turbidity");
+        });
+
+        ui.horizontal(|ui| {
+            color_edit(ui, &mut self.ground_albedo);
+            ui.label("Ground Albedo");
+        });
+
+        ui.horizontal(|ui| {
+            ui.add(
+                DragValue::new(&mut self.sun_intensity)
+                    .range(0.0..=f32::MAX)
+                    .suffix(" W/m²"),
+            );
+            ui.label("Sun Intensity");
+        });
+
+        ui.horizontal(|ui| {
+            ui.add(
+                DragValue::new(&mut self.sun_diameter)
+                    .range(0.0..=180.0)
+                    .speed(0.01)
+                    .suffix("°"),
+            );
+            ui.label("Sun Diameter");
+        });
+    }
+}
+
+impl Default for SkySettings {
+    fn default() -> Self {
+        Self {
+            time_of_day: 15.0,
+            latitude: 45.0,
+            day_of_year: 172,
+            turbidity: 3.0,
+            ground_albedo: Vector3::repeat(0.3),
+            sun_intensity: 10.0,
+            sun_diameter: 0.53,
+        }
+    }
+}
+
+impl Hash for Sky {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sun_direction.map(OrderedFloat).hash(state);
+        OrderedFloat(self.sun_cos_radius).hash(state);
+        self.sun_irradiance.map(OrderedFloat).hash(state);
+        self.ground_albedo.map(OrderedFloat).hash(state);
+        self.zenith.map(OrderedFloat).hash(state);
+        self.perez.map(|x| x.map(OrderedFloat)).hash(state);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{cmp::Ordering, time::Instant};
+
+use compute::export::{
+    egui::{
+        vec2, Color32, ComboBox, Context, DragValue, Id, LayerId, Order, Rect, Slider, Stroke,
+        StrokeKind, Ui, Vec2,
+    },
+    nalgebra::Vector2,
+};
+
+/// Splits the compute dispatch into tiles so a frame never takes much longer
+/// than the time budget, no matter how expensive the render settings are.
+pub struct Tiles {
+    pub enabled: bool,
+    pub size: u32,
+    /// Target frame time in milliseconds.
+    pub budget: f32,
+    pub order: TileOrder,
+    pub overlay: bool,
+
+    /// Tiles left to render in the current pass, the next tile is at the end.
+    pending: Vec<Vector2<u32>>,
+    /// Tiles dispatched in the last frame.
+    current: Vec<Vector2<u32>>,
+    per_frame: f32,
+    last_frame: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    Center,
+    Cursor,
+}
+
+pub struct TileDispatch {
+    pub tiles: Vec<Vector2<u32>>,
+    /// Size of the region each tile covers in pixels.
+    pub extent: Vector2<u32>,
+    /// If this dispatch completes a pass over the whole image.
+    pub finished: bool,
+}
+
+impl Tiles {
+    /// Starts a new pass on the next dispatch.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Picks the tiles to render this frame. `cursor` is in normalized screen
+    /// coordinates and is only used for [`TileOrder::Cursor`].
+    pub fn next(&mut self, window: Vector2<u32>, cursor: Option<Vector2<f32>>) -> TileDispatch {
+        let frame_time = self.last_frame.elapsed().as_secs_f32() * 1000.0;
+        self.last_frame = Instant::now();
+
+        if !self.enabled {
+            self.pending.clear();
+            self.current = vec![Vector2::zeros()];
+            return TileDispatch {
+                tiles: self.current.clone(),
+                extent: window,
+                finished: true,
+            };
+        }
+
+        // Slowly grow the tile count while under budget and back off quickly when over it
+        if frame_time > self.budget {
+            self.per_frame = (self.per_frame * 0.8).max(1.0);
+        } else {
+            self.per_frame += 1.0;
+        }
+
+        if self.pending.is_empty() {
+            let focus = match (self.order, cursor) {
+                (TileOrder::Cursor, Some(cursor)) => cursor.component_mul(&window.cast()),
+                _ => window.cast() / 2.0,
+            };
+            self.pending = spiral(window, self.size, focus);
+        }
+
+        let count = (self.per_frame as usize).min(self.pending.len());
+        self.current = self.pending.split_off(self.pending.len() - count);
+        TileDispatch {
+            tiles: self.current.clone(),
+            extent: Vector2::repeat(self.size),
+            finished: self.pending.is_empty(),
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        let old_size = self.size;
+        ui.checkbox(&mut self.enabled, "Tiled Dispatch");
+        ui.add_enabled_ui(self.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.add(Slider::new(&mut self.size, 8..=256).step_by(8.0));
+                ui.label("Tile Size");
+            });
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    DragValue::new(&mut self.budget)
+                        .range(1.0..=1000.0)
+                        .suffix("ms"),
+                );
+                ui.label("Frame Budget");
+            });
+
+            ComboBox::from_label("Tile Order")
+                .selected_text(match self.order {
+                    TileOrder::Center => "Center",
+                    TileOrder::Cursor => "Cursor",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.order, TileOrder::Center, "Center");
+                    ui.selectable_value(&mut self.order, TileOrder::Cursor, "Cursor");
+                });
+
+            ui.checkbox(&mut self.overlay, "Show Progress");
+        });
+
+        if self.size != old_size {
+            self.reset();
+        }
+    }
+
+    /// Dims the tiles that have not been rendered yet in this pass and
+    /// outlines the ones rendered in the last frame.
+    pub fn overlay(&self, ctx: &Context, window: Vector2<u32>) {
+        if !self.enabled || !self.overlay || self.pending.is_empty() {
+            return;
+        }
+
+        let screen = ctx.screen_rect();
+        let scale = screen.size() / vec2(window.x as f32, window.y as f32);
+        let painter = ctx.layer_painter(LayerId::new(Order::Background, Id::new("tiles")));
+        let tile_rect = |tile: &Vector2<u32>| {
+            let min = vec2(tile.x as f32, tile.y as f32) * scale;
+            Rect::from_min_size(screen.min + min, Vec2::splat(self.size as f32) * scale)
+        };
+
+        for tile in self.pending.iter() {
+            painter.rect_filled(tile_rect(tile), 0.0, Color32::from_black_alpha(96));
+        }
+
+        let stroke = Stroke::new(1.0, Color32::from_rgb(255, 170, 0));
+        for tile in self.current.iter() {
+            painter.rect_stroke(tile_rect(tile), 0.0, stroke, StrokeKind::Inside);
+        }
+    }
+}
+
+/// Orders the tiles covering the window in a square spiral around `focus`,
+/// reversed so the closest tile can be popped off the end first.
+fn spiral(window: Vector2<u32>, size: u32, focus: Vector2<f32>) -> Vec<Vector2<u32>> {
+    let mut tiles = (0..window.y.div_ceil(size))
+        .flat_map(|y| (0..window.x.div_ceil(size)).map(move |x| Vector2::new(x, y) * size))
+        .map(|tile| {
+            let center = tile.cast::<f32>() + Vector2::repeat(size as f32 / 2.0);
+            let offset = (center - focus) / size as f32;
+            let ring = offset.x.abs().max(offset.y.abs()).round();
+            (tile, ring, offset.y.atan2(offset.x))
+        })
+        .collect::<Vec<_>>();
+
+    tiles.sort_by(|a, b| {
+        (b.1, b.2)
+            .partial_cmp(&(a.1, a.2))
+            .unwrap_or(Ordering::Equal)
+    });
+    tiles.into_iter().map(|(tile, ..)| tile).collect()
+}
+
+impl Default for Tiles {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            size: 64,
+            budget: 16.0,
+            order: TileOrder::Center,
+            overlay: true,
+
+            pending: Vec::new(),
+            current: Vec::new(),
+            per_frame: 1.0,
+            last_frame: Instant::now(),
+        }
+    }
+}
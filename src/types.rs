@@ -1,53 +1,157 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    f32::consts::PI,
+    hash::{Hash, Hasher},
+    ops::Range,
+    path::PathBuf,
+};
 
 use bitflags::bitflags;
 use compute::{
     bindings::{BlasBuffer, StorageBuffer},
-    export::nalgebra::{Matrix4x3, Vector2, Vector3},
+    export::nalgebra::{Matrix3, Matrix4, Matrix4x3, UnitQuaternion, Vector2, Vector3, Vector4},
     misc::mutability::Immutable,
 };
-use encase::ShaderType;
 use ordered_float::OrderedFloat;
+use serde::Deserialize;
 
-use crate::camera::Camera;
+use crate::{camera::Camera, misc::next_id, sky::Sky, wgsl::wgsl_struct};
 
 pub type ModelBuffer = StorageBuffer<Vec<GpuModel>, Immutable>;
+pub type MaterialBuffer = StorageBuffer<Vec<GpuMaterial>, Immutable>;
+pub type LightBuffer = StorageBuffer<Vec<GpuLight>, Immutable>;
 pub type TransformBuffer = BlasBuffer<Matrix4x3<f32>>;
 
-#[derive(Default, ShaderType)]
-pub struct Uniform {
-    pub window: Vector2<u32>,
-    pub camera: Camera,
-    pub frame: u32,
-    pub accumulation_frame: u32,
-    pub flags: u32,
+wgsl_struct! {
+    #[derive(Default, Clone)]
+    pub struct Uniform {
+        pub window: Vector2<u32>,
+        pub camera: Camera,
+        pub frame: u32,
+        pub accumulation_frame: u32,
+        pub flags: u32,
+
+        pub exposure: f32,
+        pub environment: f32,
+        pub max_bounces: u32,
+        pub samples: u32,
+
+        /// Relative error below which a pixel is no longer sampled.
+        pub adaptive_threshold: f32,
+        pub adaptive_min_samples: u32,
+
+        /// Bounce after which paths start being terminated by russian roulette.
+        pub roulette_depth: u32,
+        /// Maximum brightness of a single indirect light contribution, zero
+        /// disables clamping.
+        pub firefly_clamp: f32,
+        /// Index of the selected model plus one, zero if nothing is selected.
+        pub selected: u32,
+
+        pub sky: Sky,
+        pub fog: Fog,
+    }
+}
+
+wgsl_struct! {
+    #[derive(Default, Clone)]
+    pub struct Fog {
+        pub medium: VolumeMaterial,
+        /// Fog only fills the space below this height.
+        pub height: f32,
+    }
+}
+
+wgsl_struct! {
+    #[derive(Default)]
+    pub struct Denoise {
+        pub iteration: u32,
+        pub iterations: u32,
+        pub strength: f32,
+    }
+}
+
+wgsl_struct! {
+    #[derive(Default, Clone, Copy)]
+    pub struct Pixel {
+        pub color: Vector3<f32>,
+        /// Running mean of the squared sample luminance, used to estimate variance.
+        pub moment: f32,
+        pub samples: u32,
+    }
+}
 
-    pub exposure: f32,
-    pub environment: f32,
-    pub max_bounces: u32,
-    pub samples: u32,
+wgsl_struct! {
+    #[derive(Default, Clone, Copy)]
+    pub struct Aov {
+        pub normal: Vector3<f32>,
+        pub depth: f32,
+        /// Index of the model hit plus one, zero for the sky.
+        pub model: u32,
+    }
 }
 
 bitflags! {
     pub struct Flags: u32 {
         const CULL_BACKFACES = 1;
+        const ADAPTIVE_SAMPLING = 2;
+        const SOBOL_SAMPLER = 4;
+        const RUSSIAN_ROULETTE = 8;
+        const PHYSICAL_SKY = 16;
     }
 }
 
-#[derive(ShaderType, Debug, Default, Clone, Copy, PartialEq)]
-pub struct Material {
-    pub tag: u32,
+bitflags! {
+    /// Features compiled into the shaders, each enabling the `#ifdef` blocks
+    /// with its name. Unlike [`Flags`] changing these rebuilds the pipelines.
+    #[derive(Clone, Copy, PartialEq)]
+    pub struct ShaderFeatures: u32 {
+        /// Sample a light at every diffuse bounce instead of relying on
+        /// paths hitting them. The sun is sampled either way, as it's too
+        /// small for paths to find. Kept on while there are enabled
+        /// [`Light`]s, which have nothing for paths to hit.
+        const NEXT_EVENT_ESTIMATION = 1;
+        /// Write the normal, depth and model of the first hit, used by the
+        /// denoiser, picking and the selection outline.
+        const AOVS = 2;
+    }
+}
 
-    pub metal: MetalMaterial,
-    pub dielectric: DielectricMaterial,
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+pub enum Material {
+    Metal(MetalMaterial),
+    Dielectric(DielectricMaterial),
+    Volume(VolumeMaterial),
+    Subsurface(SubsurfaceMaterial),
 }
 
-#[derive(ShaderType, Debug, Default, Clone, Copy, PartialEq)]
+/// An entry in the scene's material table, shared by any number of models.
+#[derive(Clone)]
+pub struct NamedMaterial {
+    pub name: String,
+    pub id: u32,
+    pub material: Material,
+}
+
+wgsl_struct! {
+    /// Every material type packed into the same layout, what each parameter
+    /// block holds depends on the tag. Unpacked again in `types.wgsl`.
+    #[derive(Default, Clone, Copy)]
+    pub struct GpuMaterial {
+        pub tag: u32,
+        pub textures: Vector2<u32>,
+        pub params: [Vector4<f32>; 4],
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct MetalMaterial {
     pub diffuse_color: Vector3<f32>,
     pub specular_color: Vector3<f32>,
 
     pub specular_probability: f32,
+    /// Perceptual roughness of the specular lobe. It's mapped to the GGX
+    /// alpha so reflections look about as blurry as they did when this
+    /// blended toward a diffuse bounce.
     pub roughness: f32,
 
     pub emission_color: Vector3<f32>,
@@ -55,56 +159,488 @@ pub struct MetalMaterial {
 
     pub diffuse_texture: u32,
     pub normal_texture: u32,
+
+    /// Stretches the specular highlight along the tangent, from -1 to 1.
+    pub anisotropy: f32,
+    /// Rotation of the tangent around the normal in degrees.
+    pub anisotropy_rotation: f32,
+
+    /// Thickness of the thin film over the specular layer in nanometers,
+    /// zero disables the film.
+    pub film_thickness: f32,
+    pub film_refractive_index: f32,
 }
 
-#[derive(ShaderType, Debug, Default, Clone, Copy, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct DielectricMaterial {
     pub refractive_index: f32,
+    /// Same as [`MetalMaterial::roughness`], zero for clear glass.
+    pub roughness: f32,
+    /// Same as [`MetalMaterial::anisotropy`].
+    pub anisotropy: f32,
+    pub anisotropy_rotation: f32,
+
+    /// Thickness of a thin film coating the outside of the surface in
+    /// nanometers, zero disables the film.
+    pub film_thickness: f32,
+    pub film_refractive_index: f32,
 }
 
-#[derive(ShaderType, Default, Clone, Copy, PartialEq)]
-pub struct GpuModel {
-    material: Material,
-    vertex_start: u32,
-    index_start: u32,
+wgsl_struct! {
+    /// A homogeneous participating medium, filling the inside of a model.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct VolumeMaterial {
+        /// Extinction coefficient per unit distance.
+        pub density: f32,
+        pub albedo: Vector3<f32>,
+        /// Henyey-Greenstein asymmetry, negative values scatter backwards.
+        pub anisotropy: f32,
+    }
 }
 
+/// Random walk subsurface scattering inside a closed mesh.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SubsurfaceMaterial {
+    pub albedo: Vector3<f32>,
+    /// Average distance light travels between scattering events, per channel.
+    pub mean_free_path: Vector3<f32>,
+    pub refractive_index: f32,
+}
+
+wgsl_struct! {
+    #[derive(Default, Clone, Copy, PartialEq)]
+    pub struct GpuModel {
+        material: u32,
+        vertex_start: u32,
+        index_start: u32,
+    }
+}
+
+/// A range of the shared vertex and index buffers. Any number of models can
+/// be instances of the same mesh.
+#[derive(Clone, Copy)]
+pub struct Mesh {
+    pub first_vertex: u32,
+    pub vertex_count: u32,
+    pub first_index: u32,
+    pub index_count: u32,
+}
+
+impl Mesh {
+    pub fn vertices(&self) -> Range<usize> {
+        self.first_vertex as usize..(self.first_vertex + self.vertex_count) as usize
+    }
+
+    pub fn indices(&self) -> Range<usize> {
+        self.first_index as usize..(self.first_index + self.index_count) as usize
+    }
+}
+
+#[derive(Clone)]
 pub struct Model {
     pub name: String,
     pub id: u32,
+    /// OBJ or glTF file the model was loaded from, reloaded when it changes.
+    pub source: Option<PathBuf>,
 
-    pub material: Material,
-    pub vertex_start: u32,
-    pub index_start: u32,
+    /// Index into the scene's material table.
+    pub material: u32,
+    /// Index into the scene's mesh list.
+    pub mesh: u32,
 
     pub position: Vector3<f32>,
     pub scale: Vector3<f32>,
-    pub rotation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+    /// Point in model space that is scaled and rotated about and placed at
+    /// `position`.
+    pub pivot: Vector3<f32>,
+}
+
+/// Order Euler angles are applied in, so `Xyz` rotates about X first and Z
+/// last. Only used for display and input, rotations are stored as quaternions.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EulerOrder {
+    #[default]
+    Xyz,
+    Xzy,
+    Yxz,
+    Yzx,
+    Zxy,
+    Zyx,
 }
 
-#[derive(ShaderType)]
-pub struct Vertex {
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LightKind {
+    Point,
+    Spot,
+    Directional,
+    Rectangle,
+    Disk,
+}
+
+#[derive(Clone)]
+pub struct Light {
+    pub name: String,
+    pub id: u32,
+    pub kind: LightKind,
+    pub enabled: bool,
+
+    pub color: Vector3<f32>,
+    /// Radiant power in watts, or irradiance in W/m² for directional lights.
+    pub intensity: f32,
+
     pub position: Vector3<f32>,
-    pub normal: Vector3<f32>,
-    pub uv: Vector2<f32>,
+    pub direction: Vector3<f32>,
+    /// Radius of point, spot and disk lights.
+    pub radius: f32,
+    /// Width and height of rectangle lights.
+    pub size: Vector2<f32>,
+    /// Inner and outer cone angles of spot lights in degrees.
+    pub cone: Vector2<f32>,
+    /// Apparent diameter of directional lights in degrees.
+    pub angular_diameter: f32,
+}
+
+wgsl_struct! {
+    #[derive(Default, Clone, Copy)]
+    pub struct GpuLight {
+        kind: u32,
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        /// Radiant intensity for point and spot lights, irradiance for
+        /// directional lights and radiance for area lights.
+        emission: Vector3<f32>,
+        size: Vector2<f32>,
+        radius: f32,
+        cos_inner: f32,
+        cos_outer: f32,
+    }
+}
+
+wgsl_struct! {
+    #[derive(Clone, Copy)]
+    pub struct Vertex {
+        pub position: Vector3<f32>,
+        pub normal: Vector3<f32>,
+        pub uv: Vector2<f32>,
+    }
 }
 
 impl Model {
-    pub fn to_gpu(&self) -> GpuModel {
+    pub fn to_gpu(&self, meshes: &[Mesh]) -> GpuModel {
+        let mesh = &meshes[self.mesh as usize];
         GpuModel {
             material: self.material,
-            vertex_start: self.vertex_start,
-            index_start: self.index_start,
+            vertex_start: mesh.first_vertex,
+            index_start: mesh.first_index,
+        }
+    }
+
+    /// Creates another model sharing this one's mesh. It isn't tied to the
+    /// source file, so it keeps its mesh if the file is reloaded.
+    pub fn instance(&self) -> Self {
+        Self {
+            name: format!("{} Instance", self.name),
+            id: next_id(),
+            source: None,
+            ..self.clone()
+        }
+    }
+
+    /// Model to world transform, scaling then rotating about the pivot before
+    /// moving it to `position`.
+    pub fn transform(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.position)
+            * self.rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&self.scale)
+            * Matrix4::new_translation(&-self.pivot)
+    }
+
+    /// Takes model space normals to world space. This is the cofactor matrix
+    /// of the transform's linear part, the inverse transpose scaled by the
+    /// determinant, flipped so mirrored models keep their normals facing out.
+    /// Matches the normal transform in `trace_ray`.
+    pub fn normal_transform(&self) -> Matrix3<f32> {
+        let linear = self.transform().fixed_view::<3, 3>(0, 0).into_owned();
+        let [a, b, c] = [0, 1, 2].map(|i| linear.column(i).into_owned());
+        let cofactor = Matrix3::from_columns(&[b.cross(&c), c.cross(&a), a.cross(&b)]);
+        cofactor * linear.determinant().signum()
+    }
+}
+
+impl EulerOrder {
+    pub const ALL: [EulerOrder; 6] = [
+        EulerOrder::Xyz,
+        EulerOrder::Xzy,
+        EulerOrder::Yxz,
+        EulerOrder::Yzx,
+        EulerOrder::Zxy,
+        EulerOrder::Zyx,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EulerOrder::Xyz => "XYZ",
+            EulerOrder::Xzy => "XZY",
+            EulerOrder::Yxz => "YXZ",
+            EulerOrder::Yzx => "YZX",
+            EulerOrder::Zxy => "ZXY",
+            EulerOrder::Zyx => "ZYX",
+        }
+    }
+
+    /// Axis indices in the order they are applied.
+    fn axes(self) -> [usize; 3] {
+        match self {
+            EulerOrder::Xyz => [0, 1, 2],
+            EulerOrder::Xzy => [0, 2, 1],
+            EulerOrder::Yxz => [1, 0, 2],
+            EulerOrder::Yzx => [1, 2, 0],
+            EulerOrder::Zxy => [2, 0, 1],
+            EulerOrder::Zyx => [2, 1, 0],
+        }
+    }
+
+    /// Builds a rotation from per-axis angles in degrees.
+    pub fn compose(self, angles: Vector3<f32>) -> UnitQuaternion<f32> {
+        self.axes()
+            .into_iter()
+            .map(|i| UnitQuaternion::from_axis_angle(&Vector3::ith_axis(i), angles[i].to_radians()))
+            .fold(UnitQuaternion::identity(), |acc, x| x * acc)
+    }
+
+    /// Splits a rotation into per-axis angles in degrees, the inverse of
+    /// [`EulerOrder::compose`]. The middle axis is kept within ±90°.
+    pub fn decompose(self, rotation: UnitQuaternion<f32>) -> Vector3<f32> {
+        let [i, j, k] = self.axes();
+        let m = rotation.to_rotation_matrix().into_inner();
+        // Odd permutations of XYZ flip the signs of the off diagonal terms
+        let sign = if (j + 3 - i) % 3 == 1 { 1.0 } else { -1.0 };
+
+        let mut angles = Vector3::zeros();
+        angles[j] = (-sign * m[(k, i)]).clamp(-1.0, 1.0).asin();
+        if m[(k, i)].abs() < 1.0 - 1e-6 {
+            angles[i] = (sign * m[(k, j)]).atan2(m[(k, k)]);
+            angles[k] = (sign * m[(j, i)]).atan2(m[(i, i)]);
+        } else {
+            // Gimbal lock, the first and last axes line up so put it all in the first
+            angles[i] = (-sign * m[(j, k)]).atan2(m[(j, j)]);
+        }
+
+        angles.map(f32::to_degrees)
+    }
+}
+
+impl LightKind {
+    pub const ALL: [LightKind; 5] = [
+        LightKind::Point,
+        LightKind::Spot,
+        LightKind::Directional,
+        LightKind::Rectangle,
+        LightKind::Disk,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightKind::Point => "Point",
+            LightKind::Spot => "Spot",
+            LightKind::Directional => "Directional",
+            LightKind::Rectangle => "Rectangle",
+            LightKind::Disk => "Disk",
+        }
+    }
+}
+
+impl Light {
+    pub fn new(kind: LightKind) -> Self {
+        Self {
+            name: format!("{} Light", kind.name()),
+            id: next_id(),
+            kind,
+            enabled: true,
+
+            color: Vector3::repeat(1.0),
+            intensity: match kind {
+                LightKind::Directional => 5.0,
+                _ => 100.0,
+            },
+
+            position: Vector3::new(0.0, 2.0, 0.0),
+            direction: -Vector3::y(),
+            radius: 0.05,
+            size: Vector2::repeat(1.0),
+            cone: Vector2::new(30.0, 45.0),
+            angular_diameter: 0.53,
+        }
+    }
+
+    pub fn to_gpu(&self) -> GpuLight {
+        let area = match self.kind {
+            LightKind::Rectangle => self.size.x * self.size.y,
+            LightKind::Disk => PI * self.radius * self.radius,
+            _ => 1.0,
+        };
+
+        let emission = self.color
+            * match self.kind {
+                // Spread the power evenly over the sphere of directions
+                LightKind::Point | LightKind::Spot => self.intensity / (4.0 * PI),
+                LightKind::Directional => self.intensity,
+                // One sided lambertian emitter
+                LightKind::Rectangle | LightKind::Disk => self.intensity / (PI * area.max(1e-6)),
+            };
+
+        let cone = match self.kind {
+            LightKind::Spot => self.cone,
+            LightKind::Directional => Vector2::repeat(self.angular_diameter / 2.0),
+            _ => Vector2::zeros(),
+        };
+
+        GpuLight {
+            kind: self.kind as u32 + 1,
+            position: self.position,
+            direction: self.direction.try_normalize(0.0).unwrap_or(-Vector3::y()),
+            emission,
+            size: self.size,
+            radius: self.radius,
+            cos_inner: cone.x.to_radians().cos(),
+            cos_outer: cone.y.to_radians().cos(),
         }
     }
 }
 
 impl Material {
-    pub fn metal(metal: MetalMaterial) -> Self {
+    /// The material's texture ids, zero meaning untextured.
+    pub fn textures_mut(&mut self) -> Vec<&mut u32> {
+        match self {
+            Material::Metal(metal) => vec![&mut metal.diffuse_texture, &mut metal.normal_texture],
+            _ => Vec::new(),
+        }
+    }
+
+    /// One material of each type, used when switching between them.
+    pub fn defaults() -> [Material; 4] {
+        [
+            Material::Metal(MetalMaterial {
+                diffuse_color: Vector3::repeat(0.8),
+                film_refractive_index: 1.33,
+                ..MetalMaterial::default()
+            }),
+            Material::Dielectric(DielectricMaterial {
+                refractive_index: 1.5,
+                film_refractive_index: 1.33,
+                ..DielectricMaterial::default()
+            }),
+            Material::Volume(VolumeMaterial {
+                density: 1.0,
+                albedo: Vector3::repeat(0.8),
+                anisotropy: 0.0,
+            }),
+            Material::Subsurface(SubsurfaceMaterial {
+                albedo: Vector3::repeat(0.9),
+                mean_free_path: Vector3::new(0.1, 0.05, 0.025),
+                refractive_index: 1.4,
+            }),
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Material::Metal(_) => "Metal",
+            Material::Dielectric(_) => "Dielectric",
+            Material::Volume(_) => "Volume",
+            Material::Subsurface(_) => "Subsurface",
+        }
+    }
+
+    pub fn to_gpu(self) -> GpuMaterial {
+        let mut out = GpuMaterial::default();
+        match self {
+            Material::Metal(x) => {
+                out.tag = 0;
+                out.textures = Vector2::new(x.diffuse_texture, x.normal_texture);
+                out.params[0] = x.diffuse_color.push(x.specular_probability);
+                out.params[1] = x.specular_color.push(x.roughness);
+                out.params[2] = x.emission_color.push(x.emission_strength);
+                out.params[3] = Vector4::new(
+                    x.anisotropy,
+                    x.anisotropy_rotation,
+                    x.film_thickness,
+                    x.film_refractive_index,
+                );
+            }
+            Material::Dielectric(x) => {
+                out.tag = 1;
+                out.params[0] = Vector4::new(
+                    x.refractive_index,
+                    x.roughness,
+                    x.anisotropy,
+                    x.anisotropy_rotation,
+                );
+                out.params[1].x = x.film_thickness;
+                out.params[1].y = x.film_refractive_index;
+            }
+            Material::Volume(x) => {
+                out.tag = 2;
+                out.params[0] = x.albedo.push(x.density);
+                out.params[1].x = x.anisotropy;
+            }
+            Material::Subsurface(x) => {
+                out.tag = 3;
+                out.params[0] = x.albedo.push(x.refractive_index);
+                out.params[1] = x.mean_free_path.push(0.0);
+            }
+        }
+        out
+    }
+
+    /// Inverse of [`Material::to_gpu`], unknown tags become the default metal.
+    pub fn from_gpu(gpu: &GpuMaterial) -> Self {
+        let p = gpu.params;
+        match gpu.tag {
+            1 => Material::Dielectric(DielectricMaterial {
+                refractive_index: p[0].x,
+                roughness: p[0].y,
+                anisotropy: p[0].z,
+                anisotropy_rotation: p[0].w,
+                film_thickness: p[1].x,
+                film_refractive_index: p[1].y,
+            }),
+            2 => Material::Volume(VolumeMaterial {
+                density: p[0].w,
+                albedo: p[0].xyz(),
+                anisotropy: p[1].x,
+            }),
+            3 => Material::Subsurface(SubsurfaceMaterial {
+                albedo: p[0].xyz(),
+                mean_free_path: p[1].xyz(),
+                refractive_index: p[0].w,
+            }),
+            _ => Material::Metal(MetalMaterial {
+                diffuse_color: p[0].xyz(),
+                specular_color: p[1].xyz(),
+                specular_probability: p[0].w,
+                roughness: p[1].w,
+                emission_color: p[2].xyz(),
+                emission_strength: p[2].w,
+                diffuse_texture: gpu.textures.x,
+                normal_texture: gpu.textures.y,
+                anisotropy: p[3].x,
+                anisotropy_rotation: p[3].y,
+                film_thickness: p[3].z,
+                film_refractive_index: p[3].w,
+            }),
+        }
+    }
+}
+
+impl NamedMaterial {
+    pub fn new(name: impl Into<String>, material: Material) -> Self {
         Self {
-            tag: 0,
-            metal,
-            dielectric: DielectricMaterial::default(),
+            name: name.into(),
+            id: next_id(),
+            material,
         }
     }
 }
@@ -113,20 +649,26 @@ impl Hash for Uniform {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.camera.hash(state);
         state.write_u32(self.flags);
-        OrderedFloat(self.exposure).hash(state);
         OrderedFloat(self.environment).hash(state);
         state.write_u32(self.max_bounces);
         state.write_u32(self.samples);
+        state.write_u32(self.roulette_depth);
+        OrderedFloat(self.firefly_clamp).hash(state);
+        self.sky.hash(state);
+        self.fog.hash(state);
     }
 }
 
-impl Hash for Material {
+impl Hash for Fog {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        match self.tag {
-            0 => self.metal.hash(state),
-            1 => self.dielectric.hash(state),
-            _ => unreachable!(),
-        }
+        self.medium.hash(state);
+        OrderedFloat(self.height).hash(state);
+    }
+}
+
+impl Hash for NamedMaterial {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.material.hash(state);
     }
 }
 
@@ -138,12 +680,52 @@ impl Hash for MetalMaterial {
         OrderedFloat(self.emission_strength).hash(state);
         OrderedFloat(self.roughness).hash(state);
         OrderedFloat(self.specular_probability).hash(state);
+        OrderedFloat(self.anisotropy).hash(state);
+        OrderedFloat(self.anisotropy_rotation).hash(state);
+        OrderedFloat(self.film_thickness).hash(state);
+        OrderedFloat(self.film_refractive_index).hash(state);
     }
 }
 
 impl Hash for DielectricMaterial {
     fn hash<H: Hasher>(&self, state: &mut H) {
         OrderedFloat(self.refractive_index).hash(state);
+        OrderedFloat(self.roughness).hash(state);
+        OrderedFloat(self.anisotropy).hash(state);
+        OrderedFloat(self.anisotropy_rotation).hash(state);
+        OrderedFloat(self.film_thickness).hash(state);
+        OrderedFloat(self.film_refractive_index).hash(state);
+    }
+}
+
+impl Hash for VolumeMaterial {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        OrderedFloat(self.density).hash(state);
+        self.albedo.map(OrderedFloat).hash(state);
+        OrderedFloat(self.anisotropy).hash(state);
+    }
+}
+
+impl Hash for SubsurfaceMaterial {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.albedo.map(OrderedFloat).hash(state);
+        self.mean_free_path.map(OrderedFloat).hash(state);
+        OrderedFloat(self.refractive_index).hash(state);
+    }
+}
+
+impl Hash for Light {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+        self.enabled.hash(state);
+        self.color.map(OrderedFloat).hash(state);
+        OrderedFloat(self.intensity).hash(state);
+        self.position.map(OrderedFloat).hash(state);
+        self.direction.map(OrderedFloat).hash(state);
+        OrderedFloat(self.radius).hash(state);
+        self.size.map(OrderedFloat).hash(state);
+        self.cone.map(OrderedFloat).hash(state);
+        OrderedFloat(self.angular_diameter).hash(state);
     }
 }
 
@@ -152,6 +734,142 @@ impl Hash for Model {
         self.material.hash(state);
         self.position.map(OrderedFloat).hash(state);
         self.scale.map(OrderedFloat).hash(state);
-        self.rotation.map(OrderedFloat).hash(state);
+        self.rotation.coords.map(OrderedFloat).hash(state);
+        self.pivot.map(OrderedFloat).hash(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use compute::export::nalgebra::{UnitQuaternion, Vector3};
+
+    use super::{EulerOrder, Model};
+
+    fn model(
+        position: Vector3<f32>,
+        scale: Vector3<f32>,
+        rotation: UnitQuaternion<f32>,
+        pivot: Vector3<f32>,
+    ) -> Model {
+        Model {
+            name: String::new(),
+            id: 0,
+            source: None,
+            material: 0,
+            mesh: 0,
+            position,
+            scale,
+            rotation,
+            pivot,
+        }
+    }
+
+    fn transform_point(model: &Model, point: Vector3<f32>) -> Vector3<f32> {
+        (model.transform() * point.push(1.0)).xyz()
+    }
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).norm() < 1e-5, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn translate_after_scale() {
+        let position = Vector3::new(1.0, -2.0, 3.0);
+        let scale = Vector3::new(2.0, 3.0, 4.0);
+        let model = model(
+            position,
+            scale,
+            UnitQuaternion::identity(),
+            Vector3::zeros(),
+        );
+
+        assert_close(transform_point(&model, Vector3::zeros()), position);
+        assert_close(
+            transform_point(&model, Vector3::repeat(1.0)),
+            position + scale,
+        );
+    }
+
+    #[test]
+    fn rotate_and_scale_about_pivot() {
+        let position = Vector3::new(4.0, 5.0, 6.0);
+        let scale = Vector3::new(2.0, 0.5, 3.0);
+        let rotation = UnitQuaternion::from_euler_angles(0.3, -1.1, 0.7);
+        let pivot = Vector3::new(1.0, 2.0, -1.0);
+        let model = model(position, scale, rotation, pivot);
+
+        assert_close(transform_point(&model, pivot), position);
+        for axis in 0..3 {
+            let offset = Vector3::ith_axis(axis).into_inner();
+            let expected = position + rotation * offset.component_mul(&scale);
+            assert_close(transform_point(&model, pivot + offset), expected);
+        }
+    }
+
+    #[test]
+    fn normals_stay_perpendicular() {
+        let rotation = UnitQuaternion::from_euler_angles(0.4, 0.9, -0.2);
+        let tangents = [Vector3::new(1.0, 1.0, 0.0), Vector3::new(0.0, 1.0, 1.0)];
+        let normal = tangents[0].cross(&tangents[1]);
+
+        for scale in [Vector3::new(3.0, 0.5, 1.0), Vector3::new(-2.0, 1.0, 0.25)] {
+            let model = model(
+                Vector3::new(1.0, 2.0, 3.0),
+                scale,
+                rotation,
+                Vector3::zeros(),
+            );
+            let linear = model.transform().fixed_view::<3, 3>(0, 0).into_owned();
+            let [a, b] = tangents.map(|x| linear * x);
+            let transformed = (model.normal_transform() * normal).normalize();
+
+            assert!(transformed.dot(&a).abs() < 1e-5);
+            assert!(transformed.dot(&b).abs() < 1e-5);
+
+            // Still on the same side of the surface, even when mirrored
+            let side = linear.determinant().signum();
+            assert!(transformed.dot(&(a.cross(&b) * side)) > 0.0);
+        }
+    }
+
+    fn assert_round_trip(order: EulerOrder, angles: Vector3<f32>) {
+        let rotation = order.compose(angles);
+        let decomposed = order.decompose(rotation);
+        let recomposed = order.compose(decomposed);
+
+        let middle = order.axes()[1];
+        assert!(decomposed[middle].abs() <= 90.0 + 1e-3);
+        assert!(
+            rotation.angle_to(&recomposed) < 1e-3,
+            "{} {angles:?} decomposed to {decomposed:?}",
+            order.name()
+        );
+    }
+
+    #[test]
+    fn euler_round_trip() {
+        for order in EulerOrder::ALL {
+            for angles in [
+                Vector3::new(30.0, -50.0, 70.0),
+                Vector3::new(-170.0, 10.0, 135.0),
+                Vector3::new(0.0, 0.0, 0.0),
+            ] {
+                assert_round_trip(order, angles);
+            }
+        }
+    }
+
+    #[test]
+    fn euler_gimbal_lock() {
+        for order in EulerOrder::ALL {
+            let [first, middle, last] = order.axes();
+            for sign in [1.0, -1.0] {
+                let mut angles = Vector3::zeros();
+                angles[first] = 40.0;
+                angles[middle] = 90.0 * sign;
+                angles[last] = -25.0;
+                assert_round_trip(order, angles);
+            }
+        }
     }
 }
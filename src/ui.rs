@@ -1,25 +1,107 @@
-use std::{fs::File, time::Instant};
+use std::{mem, time::Instant};
 
 use compute::{
     export::{
-        egui::{CollapsingHeader, Context, DragValue, Grid, Slider, Ui, Window},
+        egui::{
+            Button, Checkbox, CollapsingHeader, Color32, ComboBox, Context, DragValue, Grid, Id,
+            Key, KeyboardShortcut, Modifiers, ProgressBar, RichText, ScrollArea, Slider, Ui,
+            Window,
+        },
         nalgebra::{Vector2, Vector3},
     },
     interactive::GraphicsCtx,
 };
-use image::{codecs::png::PngEncoder, ExtendedColorType, ImageEncoder};
 
 use crate::{
     app::App,
-    misc::{hash, vec3_dragger},
-    types::{DielectricMaterial, Flags, Material, MetalMaterial},
+    consts::MAX_DENOISE_ITERATIONS,
+    materials::{load_library, presets, save_library},
+    misc::{color_edit, hash, vec3_dragger},
+    types::{
+        DielectricMaterial, EulerOrder, Flags, Light, LightKind, Material, MetalMaterial,
+        NamedMaterial, ShaderFeatures, SubsurfaceMaterial, VolumeMaterial,
+    },
 };
 
+const UNDO: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Z);
+const REDO: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::Z);
+
 pub fn ui(app: &mut App, gcx: GraphicsCtx, ctx: &Context) {
+    // Redo is checked first as shortcuts match even with extra shift held.
+    // Text fields handle these themselves while focused.
+    let mut restored = false;
+    if !ctx.wants_keyboard_input() {
+        if ctx.input_mut(|x| x.consume_shortcut(&REDO)) {
+            app.redo(1);
+            restored = true;
+        } else if ctx.input_mut(|x| x.consume_shortcut(&UNDO)) {
+            app.undo(1);
+            restored = true;
+        }
+    }
+
     let old_uniform = hash(&app.uniform);
 
     let mut flags = Flags::from_bits_truncate(app.uniform.flags);
-    app.uniform.camera.handle_movement(&gcx, ctx);
+    // Shift in the redo shortcut would otherwise also move the restored
+    // camera down, recording a new edit that clears the redo history
+    if !restored {
+        app.uniform.camera.handle_movement(&gcx, ctx);
+    }
+
+    let screen = ctx.screen_rect();
+    app.cursor = ctx.pointer_hover_pos().map(|pos| {
+        let pos = (pos - screen.min) / screen.size();
+        Vector2::new(pos.x, pos.y)
+    });
+    app.tiles.overlay(ctx, app.last_window);
+
+    // Clicking (not dragging) on the viewport selects the model under the cursor
+    let clicked = ctx.input(|x| x.pointer.primary_clicked()) && !ctx.is_pointer_over_area();
+    if let Some(cursor) = app.cursor.filter(|_| clicked) {
+        app.pick(cursor);
+    }
+
+    let picked = app.picked.lock().unwrap().take();
+    if let Some(model) = picked {
+        app.selected = model.checked_sub(1).map(|x| x as usize);
+    }
+    let reveal = picked.is_some() && app.selected.is_some();
+
+    let dropped = ctx.input(|x| x.raw.dropped_files.clone());
+    for path in dropped.into_iter().filter_map(|x| x.path) {
+        app.import(path);
+    }
+    app.poll_assets();
+    app.poll_loading();
+
+    // Analytic lights have no geometry for paths to hit, so they only show
+    // up through next event estimation
+    let nee = ShaderFeatures::NEXT_EVENT_ESTIMATION;
+    let has_lights = app.lights.iter().any(|x| x.enabled);
+    if has_lights && !app.shaders.features.contains(nee) {
+        app.set_shader_features(app.shaders.features | nee);
+    }
+
+    app.reload_shaders();
+    if let Some(error) = &app.shaders.error {
+        Window::new("Shader Error")
+            .default_width(600.0)
+            .show(ctx, |ui| {
+                ScrollArea::vertical().show(ui, |ui| {
+                    ui.label(RichText::new(error).monospace().color(Color32::RED));
+                });
+            });
+    }
+
+    let camera = &app.uniform.camera;
+    if let Some(model) = app.selected.and_then(|x| app.models.get_mut(x)) {
+        if app.gizmo.show(ctx, camera, model) {
+            app.invalidate_accumulation();
+            app.upload_models();
+        }
+    }
 
     Window::new("Ray Tracing")
         .default_width(0.0)
@@ -29,6 +111,26 @@ pub fn ui(app: &mut App, gcx: GraphicsCtx, ctx: &Context) {
                 app.last_frame.elapsed().as_secs_f32().recip()
             ));
             app.last_frame = Instant::now();
+
+            ui.label(format!("Samples: {}", app.samples_per_pixel()));
+            if let Some(stats) = app.convergence.stats() {
+                ui.label(format!(
+                    "Noise: {:.2}% (RMSE {:.4})",
+                    stats.relative_error * 100.0,
+                    stats.rmse
+                ));
+            }
+            if app.convergence.stopped {
+                ui.label("Stopped");
+            }
+
+            for loader in app.loading.iter() {
+                let progress = &loader.progress;
+                let bar = ProgressBar::new(progress.fraction().unwrap_or_default())
+                    .text(progress.status())
+                    .animate(progress.fraction().is_none());
+                ui.add(bar);
+            }
             ui.separator();
 
             ui.collapsing("Rendering", |ui| {
@@ -49,10 +151,88 @@ pub fn ui(app: &mut App, gcx: GraphicsCtx, ctx: &Context) {
 
                 ui.checkbox(&mut app.accumulate, "Accumulate");
 
+                let mut roulette = flags.contains(Flags::RUSSIAN_ROULETTE);
+                ui.checkbox(&mut roulette, "Russian Roulette");
+                flags.set(Flags::RUSSIAN_ROULETTE, roulette);
+
+                ui.add_enabled_ui(roulette, |ui| {
+                    ui.horizontal(|ui| {
+                        let max_bounces = app.uniform.max_bounces;
+                        ui.add(Slider::new(
+                            &mut app.uniform.roulette_depth,
+                            0..=max_bounces,
+                        ));
+                        ui.label("Min Depth");
+                    });
+                });
+
+                ui.horizontal(|ui| {
+                    ui.add(
+                        DragValue::new(&mut app.uniform.firefly_clamp)
+                            .range(0.0..=f32::MAX)
+                            .speed(0.1),
+                    );
+                    ui.label("Firefly Clamp");
+                })
+                .response
+                .on_hover_text("Maximum brightness of indirect light, zero disables clamping");
+
+                let mut sobol = flags.contains(Flags::SOBOL_SAMPLER);
+                ComboBox::from_label("Sampler")
+                    .selected_text(if sobol { "Sobol" } else { "Random" })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut sobol, false, "Random");
+                        ui.selectable_value(&mut sobol, true, "Sobol");
+                    });
+                flags.set(Flags::SOBOL_SAMPLER, sobol);
+
+                let mut adaptive = flags.contains(Flags::ADAPTIVE_SAMPLING);
+                ui.checkbox(&mut adaptive, "Adaptive Sampling");
+                flags.set(Flags::ADAPTIVE_SAMPLING, adaptive);
+
+                ui.add_enabled_ui(adaptive, |ui| {
+                    ui.horizontal(|ui| {
+                        let mut percent = app.uniform.adaptive_threshold * 100.0;
+                        ui.add(
+                            DragValue::new(&mut percent)
+                                .range(0.01..=100.0)
+                                .speed(0.01)
+                                .suffix("%"),
+                        );
+                        app.uniform.adaptive_threshold = percent / 100.0;
+                        ui.label("Noise Threshold");
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            DragValue::new(&mut app.uniform.adaptive_min_samples)
+                                .range(2..=u32::MAX),
+                        );
+                        ui.label("Min Samples");
+                    });
+                });
+
                 let mut cull_backfaces = flags.contains(Flags::CULL_BACKFACES);
                 ui.checkbox(&mut cull_backfaces, "Cull Backfaces");
                 flags.set(Flags::CULL_BACKFACES, cull_backfaces);
 
+                let mut features = app.shaders.features;
+                let mut sample_lights = features.contains(nee);
+                let checkbox = Checkbox::new(&mut sample_lights, "Next Event Estimation");
+                ui.add_enabled(!has_lights, checkbox)
+                    .on_hover_text("Changing this recompiles the shaders")
+                    .on_disabled_hover_text("Needed for the lights in the Lights panel");
+                features.set(nee, sample_lights);
+
+                let mut aovs = features.contains(ShaderFeatures::AOVS);
+                ui.checkbox(&mut aovs, "AOVs")
+                    .on_hover_text("Needed for the denoiser, picking and the selection outline");
+                features.set(ShaderFeatures::AOVS, aovs);
+
+                if features != app.shaders.features {
+                    app.set_shader_features(features);
+                }
+
                 ui.separator();
 
                 ui.horizontal(|ui| {
@@ -68,43 +248,169 @@ pub fn ui(app: &mut App, gcx: GraphicsCtx, ctx: &Context) {
                     ui.add(Slider::new(&mut app.uniform.environment, 0.0..=1.0));
                     ui.label("Environment");
                 });
+
+                let mut physical_sky = flags.contains(Flags::PHYSICAL_SKY);
+                ComboBox::from_label("Sky")
+                    .selected_text(if physical_sky { "Physical" } else { "Gradient" })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut physical_sky, false, "Gradient");
+                        ui.selectable_value(&mut physical_sky, true, "Physical");
+                    });
+                flags.set(Flags::PHYSICAL_SKY, physical_sky);
+
+                if physical_sky {
+                    app.sky.ui(ui);
+                }
+
+                ui.separator();
+
+                let fog = &mut app.uniform.fog;
+                ui.horizontal(|ui| {
+                    ui.add(
+                        DragValue::new(&mut fog.medium.density)
+                            .range(0.0..=f32::MAX)
+                            .speed(0.001),
+                    );
+                    ui.label("Fog Density");
+                });
+
+                ui.add_enabled_ui(fog.medium.density > 0.0, |ui| {
+                    ui.horizontal(|ui| {
+                        color_edit(ui, &mut fog.medium.albedo);
+                        ui.label("Fog Albedo");
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.add(Slider::new(&mut fog.medium.anisotropy, -0.99..=0.99));
+                        ui.label("Fog Anisotropy");
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.add(DragValue::new(&mut fog.height).speed(0.01));
+                        ui.label("Fog Height");
+                    });
+                });
+
+                ui.separator();
+
+                // Without the AOVs the filter would blur across edges
+                ui.add_enabled_ui(aovs, |ui| {
+                    ui.checkbox(&mut app.denoise_enabled, "Denoise")
+                        .on_disabled_hover_text("Needs AOVs");
+                    ui.add_enabled_ui(app.denoise_enabled, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.add(Slider::new(&mut app.denoise.strength, 0.0..=2.0));
+                            ui.label("Strength");
+                        });
+
+                        ui.horizontal(|ui| {
+                            let iterations = 1..=MAX_DENOISE_ITERATIONS;
+                            ui.add(Slider::new(&mut app.denoise.iterations, iterations));
+                            ui.label("Iterations");
+                        });
+
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                DragValue::new(&mut app.denoise_cutoff)
+                                    .range(0.0..=100.0)
+                                    .speed(0.01)
+                                    .suffix("%"),
+                            );
+                            ui.label("Disable Below Noise");
+                        });
+                    });
+                });
             });
 
-            ui.collapsing("Models", |ui| model_settings(app, ui));
+            ui.collapsing("Progressive", |ui| {
+                app.convergence.ui(ui);
+                ui.separator();
+                app.tiles.ui(ui);
+            });
+            CollapsingHeader::new("Models")
+                .open(reveal.then_some(true))
+                .show(ui, |ui| model_settings(app, ui, reveal));
+            ui.collapsing("Materials", |ui| material_library(app, ui));
+            ui.collapsing("Lights", |ui| light_settings(app, ui));
             ui.collapsing("Camera", |ui| app.uniform.camera.ui(ui));
 
             ui.separator();
 
-            if ui.button("Capture").clicked() {
-                let window = gcx.window.inner_size();
-                let window = Vector2::new(window.width, window.height) / app.screen_fraction as u32;
+            ui.horizontal(|ui| {
+                if ui.button("Capture").clicked() {
+                    app.capture();
+                }
+                ui.toggle_value(&mut app.history.show, "History");
+            });
+        });
 
-                app.accumulation_buffer.download_async(move |data| {
-                    let encoder = PngEncoder::new(File::create("out.png").unwrap());
-                    let data = data
-                        .iter()
-                        .map(|x| (x * 255.0).map(|x| x as u8))
-                        .flat_map(|x| [x.x, x.y, x.z])
-                        .collect::<Vec<_>>();
+    let mut show_history = app.history.show;
+    Window::new("History")
+        .open(&mut show_history)
+        .default_width(0.0)
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(app.history.can_undo(), Button::new("Undo"))
+                    .clicked()
+                {
+                    app.undo(1);
+                }
+                if ui
+                    .add_enabled(app.history.can_redo(), Button::new("Redo"))
+                    .clicked()
+                {
+                    app.redo(1);
+                }
+            });
+            ui.separator();
 
-                    encoder
-                        .write_image(&data, window.x, window.y, ExtendedColorType::Rgb8)
-                        .unwrap();
-                });
+            match app.history.ui(ui) {
+                Some(steps) if steps < 0 => app.undo(steps.unsigned_abs()),
+                Some(steps) => app.redo(steps as usize),
+                None => {}
             }
         });
+    app.history.show = show_history;
 
     app.uniform.flags = flags.bits();
+    app.uniform.selected = app.selected.map_or(0, |x| x as u32 + 1);
+    app.uniform.sky = app.sky.to_gpu();
     if hash(&app.uniform) != old_uniform {
         app.invalidate_accumulation();
     }
+    app.record_history(ctx);
 }
 
-fn model_settings(app: &mut App, ui: &mut Ui) {
+/// Dragged from the material library onto a model to assign it.
+struct MaterialPayload(u32);
+
+/// With `reveal` set the selected model's settings are opened.
+fn model_settings(app: &mut App, ui: &mut Ui, reveal: bool) {
+    app.gizmo.ui(ui);
+    ComboBox::from_label("Rotation Order")
+        .selected_text(app.euler_order.name())
+        .show_ui(ui, |ui| {
+            for order in EulerOrder::ALL {
+                ui.selectable_value(&mut app.euler_order, order, order.name());
+            }
+        });
+    ui.separator();
+
+    let euler_order = app.euler_order;
+    let mut mesh_users = vec![0; app.meshes.len()];
+    for model in app.models.iter() {
+        mesh_users[model.mesh as usize] += 1;
+    }
+
     let old_models = hash(&app.models);
-    for model in app.models.iter_mut() {
-        CollapsingHeader::new(&model.name)
+    let model_count = app.models.len();
+    let (mut instanced, mut duplicated, mut removed) = (None, None, None);
+    for (i, model) in app.models.iter_mut().enumerate() {
+        let selected = app.selected == Some(i);
+        let response = CollapsingHeader::new(&model.name)
             .id_salt(model.id)
+            .open((reveal && selected).then_some(true))
             .show(ui, |ui| {
                 Grid::new(&model.name).num_columns(2).show(ui, |ui| {
                     ui.label("Position");
@@ -116,37 +422,356 @@ fn model_settings(app: &mut App, ui: &mut Ui) {
                     ui.end_row();
 
                     ui.label("Rotation");
-                    vec3_dragger(ui, &mut model.rotation, |x| x.speed(0.01));
+                    let angles = euler_order.decompose(model.rotation);
+                    let mut new_angles = angles;
+                    vec3_dragger(ui, &mut new_angles, |x| x.speed(0.5).suffix("°"));
+                    if new_angles != angles {
+                        model.rotation = euler_order.compose(new_angles);
+                    }
+                    ui.end_row();
+
+                    // Moving the pivot keeps the model where it is in the world
+                    ui.label("Pivot");
+                    let mut pivot = model.pivot;
+                    vec3_dragger(ui, &mut pivot, |x| x.speed(0.01));
+                    let offset = model.scale.component_mul(&(pivot - model.pivot));
+                    model.position += model.rotation * offset;
+                    model.pivot = pivot;
+                    ui.end_row();
+
+                    ui.label("Material");
+                    let material_name = |i: u32| &app.materials[i as usize].name;
+                    ComboBox::from_id_salt(("model_material", model.id))
+                        .selected_text(material_name(model.material))
+                        .show_ui(ui, |ui| {
+                            for i in 0..app.materials.len() as u32 {
+                                ui.selectable_value(&mut model.material, i, material_name(i));
+                            }
+                        });
                     ui.end_row();
                 });
 
-                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Duplicate").clicked() {
+                        duplicated = Some(i);
+                    }
+
+                    if ui.button("Duplicate as Instance").clicked() {
+                        instanced = Some(i);
+                    }
+
+                    // The acceleration structure can't be built without any geometry
+                    let delete = ui
+                        .add_enabled(model_count > 1, Button::new("Delete"))
+                        .on_disabled_hover_text("The scene needs at least one model");
+                    if delete.clicked() {
+                        removed = Some(i);
+                    }
+                });
+
+                let users = mesh_users[model.mesh as usize];
+                if users > 1 {
+                    ui.label(format!("Mesh shared by {users} models"));
+                }
+            });
+
+        if reveal && selected {
+            response.header_response.scroll_to_me(None);
+        }
+
+        if let Some(payload) = response
+            .header_response
+            .dnd_release_payload::<MaterialPayload>()
+        {
+            model.material = payload.0;
+        }
+    }
+
+    if let Some(instanced) = instanced {
+        app.models.push(app.models[instanced].instance());
+        app.selected = Some(app.models.len() - 1);
+        app.rebuild_acceleration();
+    }
+
+    if let Some(duplicated) = duplicated {
+        app.import_error = app.duplicate_model(duplicated).err().map(|x| x.to_string());
+        app.selected = Some(app.models.len() - 1);
+    }
+
+    if let Some(removed) = removed {
+        app.remove_model(removed);
+    }
+
+    ui.separator();
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(&mut app.import_path)
+            .on_hover_text("OBJ, glTF or scene file, files can also be dropped onto the window");
+        if ui.button("Import").clicked() {
+            app.import(app.import_path.clone());
+        }
+    });
+
+    if let Some(error) = &app.import_error {
+        ui.colored_label(Color32::RED, error);
+    }
+
+    if hash(&app.models) != old_models {
+        app.invalidate_accumulation();
+        app.upload_models();
+    }
+}
+
+fn material_library(app: &mut App, ui: &mut Ui) {
+    let old_models = hash(&app.models);
+    let old_materials = hash(&app.materials);
 
-                material_settings(ui, &mut model.material);
+    let (mut duplicated, mut removed) = (None, None);
+    for (i, material) in app.materials.iter_mut().enumerate() {
+        let users = app.models.iter().filter(|x| x.material == i as u32).count();
+        CollapsingHeader::new(&material.name)
+            .id_salt(material.id)
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut material.name);
+                    ui.dnd_drag_source(
+                        Id::new(("material_drag", material.id)),
+                        MaterialPayload(i as u32),
+                        |ui| ui.label("✋ Drag to Model"),
+                    );
+                });
+
+                material_settings(ui, material.id, &mut material.material);
+
+                ui.horizontal(|ui| {
+                    if ui.button("Duplicate").clicked() {
+                        duplicated = Some(i);
+                    }
+
+                    let delete = ui
+                        .add_enabled(users == 0, Button::new("Delete"))
+                        .on_disabled_hover_text(format!("Used by {users} model(s)"));
+                    if delete.clicked() {
+                        removed = Some(i);
+                    }
+                });
             });
     }
 
+    if let Some(duplicated) = duplicated {
+        let original = &app.materials[duplicated];
+        let copy = NamedMaterial::new(format!("{} Copy", original.name), original.material);
+        app.materials.push(copy);
+    }
+
+    if let Some(removed) = removed {
+        app.materials.remove(removed);
+        for model in app.models.iter_mut() {
+            if model.material > removed as u32 {
+                model.material -= 1;
+            }
+        }
+    }
+
+    ui.horizontal_wrapped(|ui| {
+        ui.label("Add");
+        if ui.button("New").clicked() {
+            let material = Material::defaults()[0];
+            app.materials.push(NamedMaterial::new("Material", material));
+        }
+
+        for (name, material) in presets() {
+            if ui.button(name).clicked() {
+                app.materials.push(NamedMaterial::new(name, material));
+            }
+        }
+    });
+
+    ui.separator();
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(&mut app.library_path);
+        if ui.button("Import").clicked() {
+            match load_library(&app.library_path) {
+                Ok(materials) => {
+                    app.materials.extend(materials);
+                    app.library_error = None;
+                }
+                Err(err) => app.library_error = Some(err.to_string()),
+            }
+        }
+
+        if ui.button("Export").clicked() {
+            let result = save_library(&app.library_path, &app.materials);
+            app.library_error = result.err().map(|x| x.to_string());
+        }
+    });
+
+    if let Some(error) = &app.library_error {
+        ui.colored_label(Color32::RED, error);
+    }
+
     if hash(&app.models) != old_models {
         app.invalidate_accumulation();
         app.upload_models();
     }
+
+    if hash(&app.materials) != old_materials {
+        app.invalidate_accumulation();
+        app.upload_materials();
+    }
+}
+
+fn light_settings(app: &mut App, ui: &mut Ui) {
+    let old_lights = hash(&app.lights);
+
+    let mut removed = None;
+    for (i, light) in app.lights.iter_mut().enumerate() {
+        CollapsingHeader::new(&light.name)
+            .id_salt(light.id)
+            .show(ui, |ui| {
+                Grid::new(light.id).num_columns(2).show(ui, |ui| {
+                    ui.label("Enabled");
+                    ui.checkbox(&mut light.enabled, "");
+                    ui.end_row();
+
+                    ui.label("Type");
+                    ComboBox::from_id_salt(("light_kind", light.id))
+                        .selected_text(light.kind.name())
+                        .show_ui(ui, |ui| {
+                            for kind in LightKind::ALL {
+                                ui.selectable_value(&mut light.kind, kind, kind.name());
+                            }
+                        });
+                    ui.end_row();
+
+                    ui.label("Color");
+                    color_edit(ui, &mut light.color);
+                    ui.end_row();
+
+                    ui.label("Intensity");
+                    let unit = match light.kind {
+                        LightKind::Directional => " W/m²",
+                        _ => " W",
+                    };
+                    ui.add(
+                        DragValue::new(&mut light.intensity)
+                            .range(0.0..=f32::MAX)
+                            .suffix(unit),
+                    );
+                    ui.end_row();
+
+                    if light.kind != LightKind::Directional {
+                        ui.label("Position");
+                        vec3_dragger(ui, &mut light.position, |x| x.speed(0.01));
+                        ui.end_row();
+                    }
+
+                    if light.kind != LightKind::Point {
+                        ui.label("Direction");
+                        vec3_dragger(ui, &mut light.direction, |x| x.speed(0.01));
+                        ui.end_row();
+                    }
+
+                    match light.kind {
+                        LightKind::Point | LightKind::Spot | LightKind::Disk => {
+                            ui.label("Radius");
+                            ui.add(
+                                DragValue::new(&mut light.radius)
+                                    .range(0.0..=f32::MAX)
+                                    .speed(0.01),
+                            );
+                            ui.end_row();
+                        }
+                        LightKind::Rectangle => {
+                            ui.label("Size");
+                            ui.horizontal(|ui| {
+                                ui.add(DragValue::new(&mut light.size.x).speed(0.01));
+                                ui.label("×");
+                                ui.add(DragValue::new(&mut light.size.y).speed(0.01));
+                            });
+                            ui.end_row();
+                        }
+                        LightKind::Directional => {
+                            ui.label("Angular Diameter");
+                            ui.add(
+                                DragValue::new(&mut light.angular_diameter)
+                                    .range(0.0..=180.0)
+                                    .speed(0.01)
+                                    .suffix("°"),
+                            );
+                            ui.end_row();
+                        }
+                    }
+
+                    if light.kind == LightKind::Spot {
+                        // The inner angle can't pass the outer one, as the
+                        // falloff between them is undefined
+                        let (inner, outer) = (light.cone.x, light.cone.y);
+                        ui.label("Cone");
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                DragValue::new(&mut light.cone.x)
+                                    .range(0.0..=outer)
+                                    .suffix("°"),
+                            );
+                            ui.label("to");
+                            ui.add(
+                                DragValue::new(&mut light.cone.y)
+                                    .range(inner..=180.0)
+                                    .suffix("°"),
+                            );
+                        });
+                        light.cone.x = light.cone.x.min(light.cone.y);
+                        ui.end_row();
+                    }
+                });
+
+                if ui.button("Remove").clicked() {
+                    removed = Some(i);
+                }
+            });
+    }
+
+    if let Some(removed) = removed {
+        app.lights.remove(removed);
+    }
+
+    ui.horizontal_wrapped(|ui| {
+        ui.label("Add");
+        for kind in LightKind::ALL {
+            if ui.button(kind.name()).clicked() {
+                app.lights.push(Light::new(kind));
+            }
+        }
+    });
+
+    if hash(&app.lights) != old_lights {
+        app.invalidate_accumulation();
+        app.upload_lights();
+    }
 }
 
-fn material_settings(ui: &mut Ui, material: &mut Material) {
-    Grid::new("material_settings")
+fn material_settings(ui: &mut Ui, id: u32, material: &mut Material) {
+    Grid::new(("material_settings", id))
         .num_columns(2)
         .show(ui, |ui| {
             ui.label("Material Type");
             ui.horizontal(|ui| {
-                ui.selectable_value(&mut material.tag, 0, "Metal");
-                ui.selectable_value(&mut material.tag, 1, "Dielectric");
+                for default in Material::defaults() {
+                    let selected = mem::discriminant(material) == mem::discriminant(&default);
+                    if ui.selectable_label(selected, default.name()).clicked() && !selected {
+                        *material = default;
+                    }
+                }
             });
             ui.end_row();
 
-            match material.tag {
-                0 => metal_material_settings(ui, &mut material.metal),
-                1 => dielectric_material_settings(ui, &mut material.dielectric),
-                _ => unreachable!(),
+            match material {
+                Material::Metal(material) => metal_material_settings(ui, material),
+                Material::Dielectric(material) => dielectric_material_settings(ui, material),
+                Material::Volume(material) => volume_material_settings(ui, material),
+                Material::Subsurface(material) => subsurface_material_settings(ui, material),
             }
         });
 }
@@ -160,6 +785,17 @@ fn metal_material_settings(ui: &mut Ui, material: &mut MetalMaterial) {
     ui.add(Slider::new(&mut material.specular_probability, 0.0..=1.0));
     ui.end_row();
 
+    anisotropy_settings(
+        ui,
+        &mut material.anisotropy,
+        &mut material.anisotropy_rotation,
+    );
+    film_settings(
+        ui,
+        &mut material.film_thickness,
+        &mut material.film_refractive_index,
+    );
+
     ui.label("Diffuse Color");
     let diffuse_color = material.diffuse_color;
     let mut color = [diffuse_color.x, diffuse_color.y, diffuse_color.z];
@@ -195,4 +831,82 @@ fn dielectric_material_settings(ui: &mut Ui, material: &mut DielectricMaterial)
             .speed(0.01),
     );
     ui.end_row();
+
+    ui.label("Roughness");
+    ui.add(Slider::new(&mut material.roughness, 0.0..=1.0));
+    ui.end_row();
+
+    anisotropy_settings(
+        ui,
+        &mut material.anisotropy,
+        &mut material.anisotropy_rotation,
+    );
+    film_settings(
+        ui,
+        &mut material.film_thickness,
+        &mut material.film_refractive_index,
+    );
+}
+
+fn anisotropy_settings(ui: &mut Ui, anisotropy: &mut f32, rotation: &mut f32) {
+    ui.label("Anisotropy");
+    ui.add(Slider::new(anisotropy, -1.0..=1.0));
+    ui.end_row();
+
+    ui.label("Anisotropy Rotation");
+    ui.add(DragValue::new(rotation).range(0.0..=360.0).suffix("°"));
+    ui.end_row();
+}
+
+fn film_settings(ui: &mut Ui, thickness: &mut f32, refractive_index: &mut f32) {
+    ui.label("Film Thickness");
+    ui.add(DragValue::new(thickness).range(0.0..=f32::MAX).suffix("nm"));
+    ui.end_row();
+
+    ui.label("Film Refractive Index");
+    ui.add_enabled(
+        *thickness > 0.0,
+        DragValue::new(refractive_index)
+            .range(1.0..=f32::MAX)
+            .speed(0.01),
+    );
+    ui.end_row();
+}
+
+fn volume_material_settings(ui: &mut Ui, material: &mut VolumeMaterial) {
+    ui.label("Density");
+    ui.add(
+        DragValue::new(&mut material.density)
+            .range(0.0..=f32::MAX)
+            .speed(0.01),
+    );
+    ui.end_row();
+
+    ui.label("Albedo");
+    color_edit(ui, &mut material.albedo);
+    ui.end_row();
+
+    ui.label("Anisotropy");
+    ui.add(Slider::new(&mut material.anisotropy, -0.99..=0.99));
+    ui.end_row();
+}
+
+fn subsurface_material_settings(ui: &mut Ui, material: &mut SubsurfaceMaterial) {
+    ui.label("Albedo");
+    color_edit(ui, &mut material.albedo);
+    ui.end_row();
+
+    ui.label("Mean Free Path");
+    vec3_dragger(ui, &mut material.mean_free_path, |x| {
+        x.range(0.0001..=f32::MAX).speed(0.001)
+    });
+    ui.end_row();
+
+    ui.label("Refractive Index");
+    ui.add(
+        DragValue::new(&mut material.refractive_index)
+            .range(1.0..=f32::MAX)
+            .speed(0.01),
+    );
+    ui.end_row();
 }
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use compute::export::nalgebra::{Vector2, Vector3, Vector4};
+
+/// Rust types with a WGSL equivalent, used to generate the shader's struct
+/// definitions so they always match the layout encase uploads.
+pub trait WgslType {
+    fn wgsl_name() -> String;
+}
+
+pub trait WgslStruct: WgslType {
+    fn wgsl_definition() -> String;
+}
+
+/// Defines a struct deriving `ShaderType` along with its WGSL definition.
+/// A `Gpu` prefix is dropped from the WGSL name.
+macro_rules! wgsl_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(encase::ShaderType)]
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::wgsl::WgslType for $name {
+            fn wgsl_name() -> String {
+                let name = stringify!($name);
+                name.strip_prefix("Gpu").unwrap_or(name).to_owned()
+            }
+        }
+
+        impl $crate::wgsl::WgslStruct for $name {
+            fn wgsl_definition() -> String {
+                use $crate::wgsl::WgslType;
+                let mut out = format!("struct {} {{\n", Self::wgsl_name());
+                $(out += &format!("    {}: {},\n", stringify!($field), <$ty>::wgsl_name());)*
+                out + "}\n"
+            }
+        }
+    };
+}
+pub(crate) use wgsl_struct;
+
+macro_rules! wgsl_type {
+    ($($ty:ty => $name:literal),* $(,)?) => {
+        $(impl WgslType for $ty {
+            fn wgsl_name() -> String {
+                $name.to_owned()
+            }
+        })*
+    };
+}
+
+wgsl_type! {
+    u32 => "u32",
+    f32 => "f32",
+    Vector2<u32> => "vec2u",
+    Vector2<f32> => "vec2f",
+    Vector3<f32> => "vec3f",
+    Vector4<f32> => "vec4f",
+}
+
+impl<T: WgslType, const N: usize> WgslType for [T; N] {
+    fn wgsl_name() -> String {
+        format!("array<{}, {N}>", T::wgsl_name())
+    }
+}
+
+/// Expands the directives in a shader, starting from the `entry` file:
+///
+/// - `#include "file.wgsl"` inserts a file, only the first time it's included
+/// - `#ifdef NAME` / `#ifndef NAME` / `#else` / `#endif` keep or drop lines
+///   depending on whether `NAME` is one of the `defines`
+///
+/// Files are loaded with `read`, which is given the path from the include.
+pub fn preprocess(
+    entry: &str,
+    defines: &[&str],
+    read: &dyn Fn(&str) -> Result<String>,
+) -> Result<String> {
+    let mut preprocessor = Preprocessor {
+        defines,
+        read,
+        included: HashSet::new(),
+        out: String::new(),
+    };
+    preprocessor.include(entry)?;
+    Ok(preprocessor.out)
+}
+
+struct Preprocessor<'a> {
+    defines: &'a [&'a str],
+    read: &'a dyn Fn(&str) -> Result<String>,
+    included: HashSet<String>,
+    out: String,
+}
+
+/// An `#ifdef` or `#ifndef` block being processed.
+struct Conditional {
+    active: bool,
+    /// If the enclosing block is active, so `#else` doesn't enable lines
+    /// inside a dropped block.
+    parent: bool,
+    line: usize,
+    seen_else: bool,
+}
+
+impl Preprocessor<'_> {
+    fn include(&mut self, file: &str) -> Result<()> {
+        if !self.included.insert(file.to_owned()) {
+            return Ok(());
+        }
+
+        let source = (self.read)(file)?;
+        let mut stack = Vec::<Conditional>::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let context = || format!("{file}:{}", i + 1);
+            let active = stack.last().is_none_or(|x| x.active);
+
+            let Some(directive) = line.trim().strip_prefix('#') else {
+                if active {
+                    self.out += line;
+                    self.out.push('\n');
+                }
+                continue;
+            };
+
+            let (name, arg) = directive.split_once(' ').unwrap_or((directive, ""));
+            let arg = arg.trim();
+            match name {
+                "include" if active => {
+                    let path = arg.strip_prefix('"').and_then(|x| x.strip_suffix('"'));
+                    let path =
+                        path.with_context(|| format!("{}: Expected a quoted path", context()))?;
+                    self.include(path).with_context(context)?;
+                }
+                "include" => {}
+                "ifdef" | "ifndef" => {
+                    let defined = self.defines.contains(&arg);
+                    stack.push(Conditional {
+                        active: active && defined == (name == "ifdef"),
+                        parent: active,
+                        line: i + 1,
+                        seen_else: false,
+                    });
+                }
+                "else" => {
+                    let Some(block) = stack.last_mut().filter(|x| !x.seen_else) else {
+                        bail!("{}: Unexpected #else", context());
+                    };
+                    block.active = block.parent && !block.active;
+                    block.seen_else = true;
+                }
+                "endif" => {
+                    if stack.pop().is_none() {
+                        bail!("{}: Unexpected #endif", context());
+                    }
+                }
+                _ => bail!("{}: Unknown directive #{name}", context()),
+            }
+        }
+
+        if let Some(block) = stack.last() {
+            bail!("{file}:{}: Missing #endif", block.line);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{Context, Result};
+    use compute::export::nalgebra::{Vector2, Vector3};
+
+    use super::{preprocess, wgsl_struct, WgslStruct};
+
+    fn run(files: &[(&str, &str)], defines: &[&str]) -> Result<String> {
+        let read = |file: &str| -> Result<String> {
+            let source = files.iter().find(|(name, _)| *name == file);
+            source
+                .map(|(_, source)| source.to_string())
+                .with_context(|| format!("No file named {file}"))
+        };
+        preprocess(files[0].0, defines, &read)
+    }
+
+    #[test]
+    fn includes_once() {
+        let files = [
+            (
+                "main.wgsl",
+                "#include \"a.wgsl\"\n#include \"b.wgsl\"\nmain",
+            ),
+            ("a.wgsl", "#include \"b.wgsl\"\na"),
+            ("b.wgsl", "b"),
+        ];
+        assert_eq!(run(&files, &[]).unwrap(), "b\na\nmain\n");
+    }
+
+    #[test]
+    fn else_in_inactive_block() {
+        let source = "#ifdef A\n#ifdef B\nab\n#else\na\n#endif\n#else\nnone\n#endif";
+        let files = [("main.wgsl", source)];
+
+        assert_eq!(run(&files, &[]).unwrap(), "none\n");
+        assert_eq!(run(&files, &["A"]).unwrap(), "a\n");
+        assert_eq!(run(&files, &["A", "B"]).unwrap(), "ab\n");
+        assert_eq!(run(&files, &["B"]).unwrap(), "none\n");
+    }
+
+    #[test]
+    fn ifndef() {
+        let files = [("main.wgsl", "#ifndef A\nno a\n#else\na\n#endif")];
+        assert_eq!(run(&files, &[]).unwrap(), "no a\n");
+        assert_eq!(run(&files, &["A"]).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn unmatched_directives() {
+        let error = |source: &str| format!("{:#}", run(&[("main.wgsl", source)], &[]).unwrap_err());
+
+        assert_eq!(error("x\n#endif"), "main.wgsl:2: Unexpected #endif");
+        assert_eq!(error("#else"), "main.wgsl:1: Unexpected #else");
+        assert_eq!(
+            error("#ifdef A\n#else\n#else\n#endif"),
+            "main.wgsl:3: Unexpected #else"
+        );
+        assert_eq!(
+            error("#ifdef A\n#ifdef B\n#endif"),
+            "main.wgsl:1: Missing #endif"
+        );
+    }
+
+    #[test]
+    fn struct_definition() {
+        wgsl_struct! {
+            #[allow(dead_code)]
+            struct GpuExample {
+                position: Vector3<f32>,
+                size: Vector2<u32>,
+                weights: [f32; 4],
+            }
+        }
+
+        assert_eq!(
+            GpuExample::wgsl_definition(),
+            "struct Example {\n    position: vec3f,\n    size: vec2u,\n    weights: array<f32, 4>,\n}\n"
+        );
+    }
+}